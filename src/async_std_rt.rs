@@ -0,0 +1,45 @@
+//! Async variants of the port checks for async-std, running the sync checks
+//! on its blocking thread pool like those for tokio
+
+use crate::{is_free, is_free_tcp, is_free_udp, pick_unused_port, Port};
+use async_std::task::spawn_blocking;
+
+/// Check if a port is free on UDP without blocking the executor
+pub async fn is_free_udp_async_std(port: Port) -> bool {
+    spawn_blocking(move || is_free_udp(port)).await
+}
+
+/// Check if a port is free on TCP without blocking the executor
+pub async fn is_free_tcp_async_std(port: Port) -> bool {
+    spawn_blocking(move || is_free_tcp(port)).await
+}
+
+/// Check if a port is free on both TCP and UDP without blocking the executor
+pub async fn is_free_async_std(port: Port) -> bool {
+    spawn_blocking(move || is_free(port)).await
+}
+
+/// async-std version of [`pick_unused_port`](crate::pick_unused_port)
+/// ```rust
+/// # async_std::task::block_on(async {
+/// use portpicker::pick_unused_port_async_std;
+/// let port: u16 = pick_unused_port_async_std().await.expect("No ports free");
+/// # });
+/// ```
+pub async fn pick_unused_port_async_std() -> Option<Port> {
+    spawn_blocking(pick_unused_port).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_free_tcp_async_std, pick_unused_port_async_std};
+    use async_std::task;
+
+    #[test]
+    fn async_std_works() {
+        task::block_on(async {
+            let port = pick_unused_port_async_std().await.expect("No ports free");
+            assert!(is_free_tcp_async_std(port).await);
+        });
+    }
+}
@@ -0,0 +1,129 @@
+//! Async variants of the port checks for tokio. They run the sync checks on
+//! tokio's blocking thread pool, so that the probe binds never hold up other
+//! tasks on the runtime.
+
+use crate::{is_free, is_free_tcp, is_free_udp, pick_unused_port, Port};
+use futures_core::Stream;
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+/// Check if a port is free on UDP without blocking the runtime
+pub async fn is_free_udp_async(port: Port) -> bool {
+    blocking(move || is_free_udp(port)).await.unwrap_or(false)
+}
+
+/// Check if a port is free on TCP without blocking the runtime
+pub async fn is_free_tcp_async(port: Port) -> bool {
+    blocking(move || is_free_tcp(port)).await.unwrap_or(false)
+}
+
+/// Check if a port is free on both TCP and UDP without blocking the runtime
+pub async fn is_free_async(port: Port) -> bool {
+    blocking(move || is_free(port)).await.unwrap_or(false)
+}
+
+/// Async version of [`pick_unused_port`](crate::pick_unused_port)
+/// ```rust
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use portpicker::pick_unused_port_async;
+/// let port: u16 = pick_unused_port_async().await.expect("No ports free");
+/// # }
+/// ```
+pub async fn pick_unused_port_async() -> Option<Port> {
+    blocking(pick_unused_port).await.flatten()
+}
+
+// Run `check` on the blocking thread pool. `None` if it panicked or the runtime
+// shut down first.
+async fn blocking<T, F>(check: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio::task::spawn_blocking(check).await.ok()
+}
+
+/// Async version of [`free_ports`](crate::free_ports), yielding the ports in
+/// `range` that are free on both TCP and UDP as checked by [`is_free_async`].
+///
+/// A port is only probed once the next item is polled, and the probe sockets
+/// are closed before it's yielded, so each port reflects live availability.
+/// ```rust
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use futures_core::Stream;
+/// use portpicker::free_ports_stream;
+/// use std::future::poll_fn;
+/// use std::pin::pin;
+/// let mut ports = pin!(free_ports_stream(15000..16000));
+/// let first = poll_fn(|cx| ports.as_mut().poll_next(cx)).await;
+/// # }
+/// ```
+pub fn free_ports_stream(range: Range<u16>) -> impl Stream<Item = Port> {
+    FreePorts {
+        ports: range,
+        probe: None,
+    }
+}
+
+// A pending `is_free_async` check
+type Check = Pin<Box<dyn Future<Output = bool> + Send>>;
+
+struct FreePorts {
+    ports: Range<u16>,
+    // The port being checked and its pending check
+    probe: Option<(Port, Check)>,
+}
+
+impl Stream for FreePorts {
+    type Item = Port;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Port>> {
+        loop {
+            if self.probe.is_none() {
+                let port = match self.ports.next() {
+                    Some(port) => port,
+                    None => return Poll::Ready(None),
+                };
+                self.probe = Some((port, Box::pin(is_free_async(port))));
+            }
+            let (port, probe) = self.probe.as_mut().expect("probe was just set");
+            let port = *port;
+            let free = ready!(probe.as_mut().poll(cx));
+            self.probe = None;
+            if free {
+                return Poll::Ready(Some(port));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{free_ports_stream, is_free_tcp_async, pick_unused_port_async};
+    use futures_core::Stream;
+    use std::future::poll_fn;
+    use std::net::TcpListener;
+    use std::pin::pin;
+
+    #[tokio::test]
+    async fn async_works() {
+        let port = pick_unused_port_async().await.expect("No ports free");
+        assert!(is_free_tcp_async(port).await);
+    }
+
+    #[tokio::test]
+    async fn stream_test() {
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let busy = listener.local_addr().unwrap().port();
+        let mut ports = pin!(free_ports_stream(busy..busy + 1));
+        assert_eq!(poll_fn(|cx| ports.as_mut().poll_next(cx)).await, None);
+
+        let mut ports = pin!(free_ports_stream(15000..16000));
+        let port = poll_fn(|cx| ports.as_mut().poll_next(cx)).await;
+        assert!((15000..16000).contains(&port.expect("No ports free")));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::probe::{close_socket, UNSPECIFIED_V4, UNSPECIFIED_V6};
+use crate::{ipv6_available, PickError, Port, PortPicker, PortProbe};
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+/// Check if a port is free on TCP for sockets bound to the network interface
+/// `device`, such as `eth1`, with `SO_BINDTODEVICE`.
+///
+/// This can differ from [`is_free_tcp`](crate::is_free_tcp): a port held by a
+/// socket bound to another interface is still free here. Returns false if the
+/// device doesn't exist, or if binding to a device isn't permitted, which
+/// needs `CAP_NET_RAW` before Linux 5.7.
+/// ```rust
+/// use portpicker::is_free_tcp_on_device;
+/// let free = is_free_tcp_on_device(15000, "lo");
+/// ```
+pub fn is_free_tcp_on_device(port: Port, device: &str) -> bool {
+    probe(port, device, &[Type::STREAM]).is_ok()
+}
+
+/// Picks a port that is available on both TCP and UDP for sockets bound to
+/// the network interface `device`, as checked by
+/// [`is_free_tcp_on_device`]. Returns `None` if the device doesn't exist.
+/// ```rust
+/// use portpicker::pick_unused_port_on_device;
+/// if let Some(port) = pick_unused_port_on_device("lo") {
+///     println!("free on lo: {}", port);
+/// }
+/// ```
+pub fn pick_unused_port_on_device(device: &str) -> Option<Port> {
+    PortPicker::new()
+        .port_probe(DeviceProbe(device.to_owned()))
+        .pick()
+}
+
+// Binds restricted to a network interface
+struct DeviceProbe(String);
+
+impl PortProbe for DeviceProbe {
+    fn try_bind_tcp(&self, addr: SocketAddr) -> io::Result<Port> {
+        Ok(bind(&self.0, addr, Type::STREAM)?.port())
+    }
+
+    fn try_bind_udp(&self, addr: SocketAddr) -> io::Result<Port> {
+        Ok(bind(&self.0, addr, Type::DGRAM)?.port())
+    }
+}
+
+// Bind each socket type on the unspecified addresses, returning the first
+// address bound
+fn probe(port: Port, device: &str, types: &[Type]) -> Result<SocketAddr, PickError> {
+    if port == 0 {
+        return Err(PickError::NoPortsAvailable);
+    }
+    let v6 = Some(UNSPECIFIED_V6).filter(|_| ipv6_available());
+    let ips: Vec<IpAddr> = v6.into_iter().chain(Some(UNSPECIFIED_V4)).collect();
+    let mut first = None;
+    for ty in types {
+        for ip in &ips {
+            let addr = bind(device, SocketAddr::new(*ip, port), *ty).map_err(PickError::Io)?;
+            first.get_or_insert(addr);
+        }
+    }
+    first.ok_or(PickError::NoPortsAvailable)
+}
+
+// Bind a socket restricted to `device`, returning the bound address
+fn bind(device: &str, addr: SocketAddr, ty: Type) -> io::Result<SocketAddr> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    socket.bind_device(Some(device.as_bytes()))?;
+    socket.bind(&addr.into())?;
+    if ty == Type::STREAM {
+        socket.listen(128)?;
+    }
+    close_socket(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_free_tcp_on_device, pick_unused_port_on_device};
+
+    #[test]
+    fn device_test() {
+        assert!(!is_free_tcp_on_device(15000, "no-such-device0"));
+        assert_eq!(pick_unused_port_on_device("no-such-device0"), None);
+        if let Some(port) = pick_unused_port_on_device("lo") {
+            assert!(is_free_tcp_on_device(port, "lo"));
+        }
+    }
+}
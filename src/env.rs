@@ -0,0 +1,48 @@
+use crate::{inclusive_len, Port, DEFAULT_RANDOM_RANGE};
+use std::env;
+use std::sync::OnceLock;
+
+// Overrides the range random candidates are drawn from
+const RANGE_VAR: &str = "PORTPICKER_RANGE";
+
+// First port and number of ports to draw random candidates from: the
+// `PORTPICKER_RANGE` setting, read once, or `DEFAULT_RANDOM_RANGE`
+pub(crate) fn random_range() -> (Port, usize) {
+    static RANGE: OnceLock<(Port, usize)> = OnceLock::new();
+    *RANGE.get_or_init(|| {
+        let default = (DEFAULT_RANDOM_RANGE.start, DEFAULT_RANDOM_RANGE.len());
+        match env::var(RANGE_VAR) {
+            Ok(value) => parse_range(&value).unwrap_or_else(|| {
+                warn!("ignoring malformed {}={:?}", RANGE_VAR, value);
+                default
+            }),
+            Err(_) => default,
+        }
+    })
+}
+
+// Parse an inclusive, non-empty `LOW-HIGH` range
+fn parse_range(value: &str) -> Option<(Port, usize)> {
+    let (low, high) = value.trim().split_once('-')?;
+    let low: Port = low.trim().parse().ok()?;
+    let high: Port = high.trim().parse().ok()?;
+    if low > high {
+        return None;
+    }
+    Some((low, inclusive_len(&(low..=high))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_range;
+
+    #[test]
+    fn env_range_test() {
+        assert_eq!(parse_range("30000-31000"), Some((30000, 1001)));
+        assert_eq!(parse_range(" 1-65535 "), Some((1, 65535)));
+        assert_eq!(parse_range("5000-5000"), Some((5000, 1)));
+        assert_eq!(parse_range("31000-30000"), None);
+        assert_eq!(parse_range("30000"), None);
+        assert_eq!(parse_range("a-b"), None);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{claim_if_free, inclusive_len, pick_unused_port_range, random_offset, wrapping_scan};
+use crate::{Port, PortPicker, DEFAULT_RANDOM_RANGE};
+use std::ops::RangeInclusive;
+
+// IANA suggested dynamic port range, used where the OS setting is unknown
+const IANA_DYNAMIC: RangeInclusive<u16> = 49152..=65535;
+
+/// The range the OS assigns ephemeral (outbound and port 0) ports from.
+///
+/// Read from `/proc/sys/net/ipv4/ip_local_port_range` on Linux and the
+/// `net.inet.ip.portrange` sysctls on macOS and FreeBSD. Other platforms get the
+/// IANA dynamic range `49152..=65535`. Returns `None` if the setting exists
+/// but can't be read. The range is inclusive, so that an upper bound of 65535
+/// can be represented.
+pub fn os_ephemeral_range() -> Option<RangeInclusive<u16>> {
+    let (low, high) = read_range()?;
+    if low > high {
+        return None;
+    }
+    Some(low..=high)
+}
+
+#[cfg(target_os = "linux")]
+fn read_range() -> Option<(Port, Port)> {
+    let contents = std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range").ok()?;
+    let mut parts = contents.split_whitespace();
+    let low = parts.next()?.parse().ok()?;
+    let high = parts.next()?.parse().ok()?;
+    Some((low, high))
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+fn read_range() -> Option<(Port, Port)> {
+    let low = sysctl_int("net.inet.ip.portrange.first\0")?;
+    let high = sysctl_int("net.inet.ip.portrange.last\0")?;
+    Some((low, high))
+}
+
+// Read a numeric sysctl, `name` must be nul-terminated
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+fn sysctl_int(name: &str) -> Option<Port> {
+    use std::convert::TryFrom;
+
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>();
+    // Safety: `name` is nul-terminated and `value`/`len` describe a valid buffer
+    let ret = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr() as *const libc::c_char,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    Port::try_from(value).ok()
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+)))]
+fn read_range() -> Option<(Port, Port)> {
+    Some((*IANA_DYNAMIC.start(), *IANA_DYNAMIC.end()))
+}
+
+/// Picks an available port that is available on both TCP and UDP and lies
+/// outside the OS ephemeral range, so it can't collide with ports the kernel
+/// hands out to outgoing connections. Ports below the ephemeral range (but
+/// above 1023) are tried first, then those above it. The OS is never asked to
+/// assign a port, as it would assign an ephemeral one.
+///
+/// If the ephemeral range can't be determined, the IANA dynamic range
+/// `49152..=65535` is avoided instead.
+/// ```rust
+/// use portpicker::pick_unused_port_avoiding_ephemeral;
+/// let port: u16 = pick_unused_port_avoiding_ephemeral().expect("No ports free");
+/// ```
+pub fn pick_unused_port_avoiding_ephemeral() -> Option<Port> {
+    let ephemeral = os_ephemeral_range().unwrap_or(IANA_DYNAMIC);
+    let (below, above) = outside(&ephemeral);
+
+    PortPicker::new()
+        .range_inclusive(below)
+        .pick()
+        .or_else(|| PortPicker::new().range_inclusive(above?).pick())
+}
+
+/// Picks a random port that is available on both TCP and UDP anywhere outside
+/// the OS ephemeral range, the most robust choice for long-lived test servers.
+///
+/// A port inside the ephemeral range can be free when picked and then be
+/// assigned by the kernel as the source port of some unrelated outgoing
+/// connection before the server binds it, or while the server is restarted,
+/// making the test fail intermittently. Ports outside it are only ever taken by
+/// explicit binds. Unlike [`pick_unused_port_avoiding_ephemeral`], the ports
+/// below and above the ephemeral range are equally likely. If the ephemeral
+/// range can't be determined, the port is picked from
+/// [`DEFAULT_RANDOM_RANGE`](crate::DEFAULT_RANDOM_RANGE) instead.
+/// ```rust
+/// use portpicker::pick_stable_unused_port;
+/// let port: u16 = pick_stable_unused_port().expect("No ports free");
+/// ```
+pub fn pick_stable_unused_port() -> Option<Port> {
+    let ephemeral = match os_ephemeral_range() {
+        Some(range) => range,
+        None => return pick_unused_port_range(DEFAULT_RANDOM_RANGE),
+    };
+    let (below, above) = outside(&ephemeral);
+    let below_len = inclusive_len(&below);
+    let above_len = above.as_ref().map_or(0, inclusive_len);
+    let above_start = above.map_or(0, |range| *range.start());
+    // Index `i` counts the ports below the ephemeral range, then those above it
+    let port = |i: Port| {
+        if (i as usize) < below_len {
+            *below.start() + i
+        } else {
+            above_start + (i - below_len as Port)
+        }
+    };
+    let len = below_len + above_len;
+    wrapping_scan(0, len, random_offset(len))
+        .map(port)
+        .find(|port| claim_if_free(*port))
+}
+
+// The unprivileged ports below `ephemeral`, which may be empty, and those
+// above it unless it ends at 65535
+fn outside(ephemeral: &RangeInclusive<u16>) -> (RangeInclusive<u16>, Option<RangeInclusive<u16>>) {
+    let below = 1024..=ephemeral.start().saturating_sub(1);
+    let above = ephemeral.end().checked_add(1);
+    (below, above.map(|start| start.max(1024)..=Port::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{os_ephemeral_range, pick_stable_unused_port, pick_unused_port_avoiding_ephemeral};
+
+    #[test]
+    fn ephemeral_test() {
+        let port = pick_unused_port_avoiding_ephemeral().expect("No ports free");
+        assert!(port >= 1024);
+        if let Some(range) = os_ephemeral_range() {
+            assert!(!range.contains(&port));
+        }
+    }
+
+    #[test]
+    fn stable_test() {
+        let port = pick_stable_unused_port().expect("No ports free");
+        assert!(port >= 1024);
+        if let Some(range) = os_ephemeral_range() {
+            assert!(!range.contains(&port));
+        }
+    }
+
+    #[test]
+    fn outside_test() {
+        use super::outside;
+
+        let (below, above) = outside(&(49152..=65535));
+        assert_eq!((below, above), (1024..=49151, None));
+        let (below, above) = outside(&(32768..=60999));
+        assert_eq!((below, above), (1024..=32767, Some(61000..=65535)));
+        assert!(outside(&(0..=1000)).0.is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::Protocol;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+
+/// Reason why no port could be picked
+#[derive(Debug)]
+pub enum PickError {
+    /// Every probed port was already in use
+    NoPortsAvailable,
+    /// The OS refused a bind for a reason other than the port being in use.
+    /// Carries the last such error seen.
+    Io(io::Error),
+    /// Binding to an IPv6 address failed, most likely because IPv6 is
+    /// disabled on this host
+    Ipv6Unavailable,
+    /// The range to pick from contains no ports, such as `0..0` or a
+    /// reversed range
+    EmptyRange,
+    /// The port is in use for `protocol` on `addr`, as reported by
+    /// [`ensure_port_free`](crate::ensure_port_free)
+    InUse {
+        protocol: Protocol,
+        addr: SocketAddr,
+    },
+    /// A requested port is neither a number from 0 to 65535 nor `auto`, as
+    /// reported by [`resolve_requested_port`](crate::resolve_requested_port)
+    InvalidPort(String),
+    /// The address passed to [`PortPicker::v6_addr`](crate::PortPicker::v6_addr)
+    /// is an IPv4-mapped address, which would bind IPv4 instead
+    NotIpv6(Ipv6Addr),
+}
+
+impl PickError {
+    // Classify an error returned from binding `addr`
+    pub(crate) fn from_bind(addr: SocketAddr, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::AddrInUse | io::ErrorKind::PermissionDenied => PickError::Io(err),
+            _ if addr.is_ipv6() => PickError::Ipv6Unavailable,
+            _ => PickError::Io(err),
+        }
+    }
+
+    // Whether this error only means that the port was taken
+    pub(crate) fn is_busy(&self) -> bool {
+        match self {
+            PickError::NoPortsAvailable | PickError::InUse { .. } => true,
+            PickError::Io(err) => err.kind() == io::ErrorKind::AddrInUse,
+            PickError::Ipv6Unavailable
+            | PickError::EmptyRange
+            | PickError::InvalidPort(_)
+            | PickError::NotIpv6(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for PickError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PickError::NoPortsAvailable => write!(f, "no free ports available"),
+            PickError::Io(err) => write!(f, "failed to bind: {}", err),
+            PickError::Ipv6Unavailable => write!(f, "IPv6 is unavailable"),
+            PickError::EmptyRange => write!(f, "the port range is empty"),
+            PickError::InUse { protocol, addr } => {
+                let protocol = match protocol {
+                    Protocol::Tcp => "TCP",
+                    Protocol::Udp => "UDP",
+                    Protocol::Both => "TCP/UDP",
+                };
+                let (ip, port) = (addr.ip(), addr.port());
+                write!(f, "{} port {} is already in use on {}", protocol, port, ip)
+            }
+            PickError::InvalidPort(spec) => write!(f, "invalid port {:?}", spec),
+            PickError::NotIpv6(ip) => write!(f, "{} is an IPv4-mapped address", ip),
+        }
+    }
+}
+
+impl Error for PickError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PickError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PickError {
+    fn from(err: io::Error) -> Self {
+        PickError::Io(err)
+    }
+}
+
+/// Unwraps [`PickError::Io`], and otherwise wraps the error with the kind
+/// `AddrNotAvailable` for IPv6, `InvalidInput` for an empty range, invalid
+/// port or IPv4-mapped address, or `AddrInUse` for a busy port
+impl From<PickError> for io::Error {
+    fn from(err: PickError) -> Self {
+        match err {
+            PickError::Io(err) => err,
+            PickError::NoPortsAvailable => io::Error::new(io::ErrorKind::AddrInUse, err),
+            PickError::Ipv6Unavailable => io::Error::new(io::ErrorKind::AddrNotAvailable, err),
+            PickError::EmptyRange | PickError::InvalidPort(_) | PickError::NotIpv6(_) => {
+                io::Error::new(io::ErrorKind::InvalidInput, err)
+            }
+            PickError::InUse { .. } => io::Error::new(io::ErrorKind::AddrInUse, err),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::probe::{UNSPECIFIED_V4, UNSPECIFIED_V6};
+use crate::{ipv6_available, release_port, Port};
+use socket2::{Domain, Protocol as SockProtocol, Socket, Type};
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::ptr;
+
+/// Keeps a port bound on both TCP and UDP until dropped. Dropping the guard
+/// also releases the port with [`release_port`](crate::release_port).
+///
+/// Both sockets are bound to the unspecified IPv6 address as dual-stack
+/// sockets, covering IPv4 too, or to the unspecified IPv4 address if IPv6 is
+/// unavailable. The TCP socket is not listening yet. To use the port while the
+/// guard is alive, the caller must bind its own sockets with the same option
+/// the guard sets: `SO_REUSEPORT` for TCP on Unix, and `SO_REUSEADDR` for UDP
+/// and on Windows. A plain `TcpListener::bind` or `UdpSocket::bind` fails until
+/// the guard is dropped. Alternatively use [`PortGuard::into_inner`] to take
+/// over the sockets directly.
+#[derive(Debug)]
+pub struct PortGuard {
+    port: Port,
+    tcp: Socket,
+    udp: Socket,
+}
+
+impl PortGuard {
+    /// Bind both TCP and UDP on `port`
+    pub(crate) fn bind(port: Port) -> io::Result<Self> {
+        let ip = if ipv6_available() {
+            UNSPECIFIED_V6
+        } else {
+            UNSPECIFIED_V4
+        };
+        let addr = SocketAddr::new(ip, port);
+        let tcp = reusable_socket(addr, Type::STREAM, SockProtocol::TCP)?;
+        let udp = reusable_socket(addr, Type::DGRAM, SockProtocol::UDP)?;
+        Ok(PortGuard { port, tcp, udp })
+    }
+
+    /// The reserved port
+    pub fn port(&self) -> Port {
+        self.port
+    }
+
+    /// Consume the guard, returning the TCP listener and UDP socket holding
+    /// the port. The TCP socket is put into listening state first.
+    pub fn into_inner(self) -> io::Result<(TcpListener, UdpSocket)> {
+        let this = ManuallyDrop::new(self);
+        // Safety: `this` is never used or dropped after moving the sockets out
+        let (tcp, udp) = unsafe { (ptr::read(&this.tcp), ptr::read(&this.udp)) };
+        release_port(this.port);
+        tcp.listen(128)?;
+        Ok((tcp.into(), udp.into()))
+    }
+}
+
+impl Drop for PortGuard {
+    fn drop(&mut self) {
+        release_port(self.port);
+    }
+}
+
+/// A free port, recording whether it's still held, as returned by
+/// [`probe_unused_port`](crate::probe_unused_port) and
+/// [`hold_unused_port`](crate::hold_unused_port)
+#[derive(Debug)]
+pub enum PortAvailability {
+    /// Found free, but nothing holds it, so another process may take it
+    /// before it's used. This is what [`pick_unused_port`](crate::pick_unused_port)
+    /// returns.
+    Probed(Port),
+    /// Bound and held until the guard is dropped
+    Held(PortGuard),
+}
+
+impl PortAvailability {
+    /// The free port
+    pub fn port(&self) -> Port {
+        match self {
+            PortAvailability::Probed(port) => *port,
+            PortAvailability::Held(guard) => guard.port(),
+        }
+    }
+
+    /// Whether the port is still held, so that no other process can take it
+    pub fn is_held(&self) -> bool {
+        matches!(self, PortAvailability::Held(_))
+    }
+}
+
+// Bind a socket that the caller can bind again with the same option set, on
+// both families for an IPv6 address
+fn reusable_socket(addr: SocketAddr, ty: Type, proto: SockProtocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(proto))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    // Two TCP sockets with SO_REUSEADDR may share a port on Unix as long as
+    // neither is listening, so std's plain bind, which sets it, would succeed
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    if ty == Type::STREAM {
+        socket.set_reuse_port(true)?;
+    } else {
+        socket.set_reuse_address(true)?;
+    }
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
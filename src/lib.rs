@@ -1,109 +1,2837 @@
+use probe::{BindOptions, DEFAULT_BIND, UNSPECIFIED_V4, UNSPECIFIED_V6};
+#[cfg(feature = "rand")]
 use rand::prelude::*;
-use std::net::{
-    Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6, TcpListener, ToSocketAddrs, UdpSocket,
+use socket2::{Domain, Socket, Type};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use std::num::NonZeroU16;
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Debug-level event about the picking, compiled out without the `log` feature
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        log::debug!($($arg)*);
+        #[cfg(not(feature = "log"))]
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    }};
+}
+
+// Warning about the configuration, compiled out without the `log` feature
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        log::warn!($($arg)*);
+        #[cfg(not(feature = "log"))]
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    }};
+}
+
+#[cfg(feature = "async-std")]
+mod async_std_rt;
+#[cfg(feature = "tokio")]
+mod async_tokio;
+#[cfg(target_os = "linux")]
+mod device;
+mod env;
+mod ephemeral;
+mod error;
+mod guard;
+mod picker;
+mod pool;
+mod probe;
+mod range;
+mod registry;
+mod remote;
+mod status;
+#[cfg(feature = "stress")]
+mod stress;
+mod wellknown;
+
+#[cfg(feature = "async-std")]
+pub use async_std_rt::{
+    is_free_async_std, is_free_tcp_async_std, is_free_udp_async_std, pick_unused_port_async_std,
+};
+#[cfg(feature = "tokio")]
+pub use async_tokio::{
+    free_ports_stream, is_free_async, is_free_tcp_async, is_free_udp_async, pick_unused_port_async,
+};
+#[cfg(target_os = "linux")]
+pub use device::{is_free_tcp_on_device, pick_unused_port_on_device};
+pub use ephemeral::{
+    os_ephemeral_range, pick_stable_unused_port, pick_unused_port_avoiding_ephemeral,
+};
+pub use error::PickError;
+pub use guard::{PortAvailability, PortGuard};
+pub use picker::PortPicker;
+pub use pool::{PortLease, PortPool};
+pub use probe::{ipv6_available, reset_ipv6_cache, OsProbe, PortProbe};
+pub use range::{pick_unused_port_in, validate_range, PortRange};
+pub use registry::release_port;
+pub use remote::is_listening;
+pub use status::{diagnose, free_families_tcp, free_families_udp, probe, Diagnosis, PortStatus};
+#[cfg(feature = "stress")]
+pub use stress::{stress_pick, StressReport};
+pub use wellknown::{
+    pick_unused_port_avoiding_services, pick_unused_port_avoiding_wellknown, port_class,
+    requires_privilege, PortClass, WELL_KNOWN_PORTS,
 };
-use std::ops::Range;
 
 pub type Port = u16;
 
-// Try to bind to a socket using UDP
-fn test_bind_udp<A: ToSocketAddrs>(addr: A) -> Option<Port> {
-    Some(UdpSocket::bind(addr).ok()?.local_addr().ok()?.port())
+/// Transport protocol(s) a port must be free on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// Only check TCP
+    Tcp,
+    /// Only check UDP
+    Udp,
+    /// Check both TCP and UDP
+    Both,
+}
+
+/// Address families the unspecified-address binds use, and in which order
+///
+/// With both families, a port must be free on both to count as free, and the
+/// first is preferred when asking the OS to assign a port. IPv6 is skipped when
+/// it's unavailable on the host, see [`ipv6_available`], unless it's the only
+/// family: then every bind fails and no port is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Family {
+    /// IPv6, then IPv4
+    #[default]
+    V6First,
+    /// IPv4, then IPv6
+    V4First,
+    /// Only IPv4
+    V4Only,
+    /// Only IPv6, failing if it's unavailable
+    V6Only,
+}
+
+/// How the pickers search for a free port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Strategy {
+    /// Try random ports, then ask the OS to assign one
+    #[default]
+    RandomFirst,
+    /// Only ask the OS to assign a port, which is the fastest
+    OsOnly,
+    /// Only try random ports, never falling back to the OS. Without the `rand`
+    /// feature there are no random ports, so nothing is found.
+    RandomOnly,
+}
+
+/// The range [`pick_unused_port`] draws its random candidates from, before
+/// asking the OS
+pub const DEFAULT_RANDOM_RANGE: Range<u16> = 15000..25000;
+
+/// Check if a port is free on UDP on the given address only
+pub fn is_free_udp_on(ip: IpAddr, port: Port) -> bool {
+    DEFAULT_BIND.probe_on(Protocol::Udp, ip, port).is_ok()
+}
+
+/// Check if a port is free on TCP on the given address only
+pub fn is_free_tcp_on(ip: IpAddr, port: Port) -> bool {
+    DEFAULT_BIND.probe_on(Protocol::Tcp, ip, port).is_ok()
 }
 
-// Try to bind to a socket using TCP
-fn test_bind_tcp<A: ToSocketAddrs>(addr: A) -> Option<Port> {
-    Some(TcpListener::bind(addr).ok()?.local_addr().ok()?.port())
+/// Check if a port is free on both TCP and UDP on the given address only
+pub fn is_free_on(ip: IpAddr, port: Port) -> bool {
+    DEFAULT_BIND.probe_on(Protocol::Both, ip, port).is_ok()
+}
+
+/// Check if a port is free on the given protocol(s) on every one of `ips`. An
+/// empty slice checks the unspecified addresses, like [`is_free_for`].
+pub fn is_free_on_all(ips: &[IpAddr], port: Port, proto: Protocol) -> bool {
+    DEFAULT_BIND.probe_on_all(proto, ips, port).is_ok()
 }
 
 /// Check if a port is free on UDP
 pub fn is_free_udp(port: Port) -> bool {
-    let ipv4 = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
-    let ipv6 = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0);
-
-    test_bind_udp(ipv6).is_some() && test_bind_udp(ipv4).is_some()
+    DEFAULT_BIND.probe_udp(port).is_ok()
 }
 
 /// Check if a port is free on TCP
 pub fn is_free_tcp(port: Port) -> bool {
-    let ipv4 = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
-    let ipv6 = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0);
+    DEFAULT_BIND.probe_tcp(port).is_ok()
+}
+
+/// Check if a port is free on TCP for a dual-stack server, with a single bind
+/// of an IPv6 socket on `[::]` that has `IPV6_V6ONLY` disabled. This models
+/// how such a server actually listens, and takes one bind instead of two.
+///
+/// Falls back to [`is_free_tcp`] where IPv6 or dual-stack sockets aren't
+/// available, such as on OpenBSD. On Linux, [`is_free_tcp`] itself uses the
+/// single bind, as it conflicts with the same sockets as the two binds there.
+/// ```rust
+/// use portpicker::is_free_dualstack_tcp;
+/// let free = is_free_dualstack_tcp(15000);
+/// ```
+pub fn is_free_dualstack_tcp(port: Port) -> bool {
+    if port == 0 {
+        return false;
+    }
+    match probe::dualstack_tcp(port) {
+        Some(result) => result.is_ok(),
+        None => is_free_tcp(port),
+    }
+}
+
+/// Check if a port is free on TCP, skipping the UDP binds of [`is_free`].
+/// The same check as [`is_free_tcp`].
+pub fn is_free_tcp_only(port: Port) -> bool {
+    is_free_tcp(port)
+}
 
-    test_bind_tcp(ipv6).is_some() && test_bind_tcp(ipv4).is_some()
+/// Check if a port is free on UDP for a socket that doesn't share it.
+///
+/// Platforms differ in when two UDP sockets may bind the same port. Linux and
+/// the BSDs only allow it if both set `SO_REUSEADDR` or `SO_REUSEPORT`, while
+/// on Windows a plain bind can succeed next to another socket unless
+/// `SO_EXCLUSIVEADDRUSE` is set. Unlike [`is_free_udp`], which relies on the
+/// platform defaults on Unix, this check clears both options explicitly there,
+/// and sets `SO_EXCLUSIVEADDRUSE` on Windows, so that it passes only if the
+/// port isn't held by any other socket.
+/// ```rust
+/// use portpicker::is_free_udp_exclusive;
+/// let free = is_free_udp_exclusive(15000);
+/// ```
+pub fn is_free_udp_exclusive(port: Port) -> bool {
+    DEFAULT_BIND.probe_udp_exclusive(port).is_ok()
+}
+
+/// Check if a port is free on TCP, probing with `SO_REUSEADDR` set.
+///
+/// A port with connections lingering in `TIME_WAIT` is then reported free, as
+/// it would be for a server that sets the option itself. Note that std's
+/// `TcpListener::bind` already sets `SO_REUSEADDR` on Unix, so this mostly
+/// makes a difference on other platforms.
+pub fn is_free_tcp_reuse(port: Port) -> bool {
+    BindOptions {
+        reuse_addr: true,
+        ..DEFAULT_BIND
+    }
+    .probe_tcp(port)
+    .is_ok()
+}
+
+/// Check if a port is free on TCP for a server that sets `SO_REUSEPORT`,
+/// meaning that no socket holds it exclusively. A port held only by other
+/// `SO_REUSEPORT` sockets counts as free, though Linux also requires them to
+/// belong to the same user.
+/// ```rust
+/// use portpicker::is_free_tcp_reuseport;
+/// let free = is_free_tcp_reuseport(15000);
+/// ```
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn is_free_tcp_reuseport(port: Port) -> bool {
+    reuseport().probe_tcp(port).is_ok()
+}
+
+/// Like [`is_free_tcp_reuseport`], but for UDP
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn is_free_udp_reuseport(port: Port) -> bool {
+    reuseport().probe_udp(port).is_ok()
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn reuseport() -> BindOptions {
+    BindOptions {
+        reuse_port: true,
+        ..DEFAULT_BIND
+    }
+}
+
+/// Check if a port can be used as the source port of an outbound TCP
+/// connection, as a client binding it before `connect` would.
+///
+/// The other checks bind a listening socket, which proves that a server can
+/// listen on the port but not that a client can connect from it: with
+/// `SO_REUSEADDR` set, a source port is only refused if the same connection
+/// already exists or the port is held by a listener. This binds the port
+/// with `SO_REUSEADDR` on the unspecified IPv4 address and connects to a
+/// temporary listener on `127.0.0.1`. The connection is reset when closed,
+/// so it leaves nothing in `TIME_WAIT`.
+/// ```rust
+/// use portpicker::is_free_as_source;
+/// let free = is_free_as_source(15000);
+/// ```
+pub fn is_free_as_source(port: Port) -> bool {
+    if port == 0 {
+        return false;
+    }
+    let connected = || -> io::Result<()> {
+        let target = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+        socket.connect(&target.local_addr()?.into())?;
+        socket.set_linger(Some(Duration::from_secs(0)))
+    };
+    connected().is_ok()
 }
 
 /// Check if a port is free on both TCP and UDP
+///
+/// A port counts as free when it can be bound on the unspecified IPv6 and
+/// IPv4 addresses. On Windows the probes set `SO_EXCLUSIVEADDRUSE`, as a
+/// plain bind can succeed there even though another application is already
+/// using the port. Other platforms use a plain bind.
+///
+/// The probe sockets are closed before this returns, so the port can be bound
+/// again right away. They're never connected and leave nothing in `TIME_WAIT`.
+/// A server that is already listening shows up as busy, but one that is still
+/// starting up can take the port between this check and the caller's bind.
 pub fn is_free(port: Port) -> bool {
-    is_free_tcp(port) && is_free_udp(port)
+    port_bind_result(port).is_ok()
+}
+
+/// Polls [`is_free`] every `poll_interval` until the port is free or `timeout`
+/// has elapsed, returning whether it became free. The last sleep is cut short
+/// at the deadline, so the port is checked once more right at `timeout`. A zero
+/// `poll_interval` is treated as one millisecond rather than spinning.
+/// ```rust
+/// use portpicker::wait_until_free;
+/// use std::time::Duration;
+/// let port = 15000;
+/// if !wait_until_free(port, Duration::from_secs(5), Duration::from_millis(100)) {
+///     eprintln!("port {} is still in use", port);
+/// }
+/// ```
+pub fn wait_until_free(port: Port, timeout: Duration, poll_interval: Duration) -> bool {
+    let poll_interval = poll_interval.max(Duration::from_millis(1));
+    let deadline = Instant::now().checked_add(timeout);
+    loop {
+        if is_free(port) {
+            return true;
+        }
+        let sleep = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if left > Duration::ZERO => poll_interval.min(left),
+                _ => return false,
+            },
+            None => poll_interval,
+        };
+        thread::sleep(sleep);
+    }
+}
+
+/// Binds a port like [`is_free`], but returns the error of the first bind that
+/// failed instead of `false`. This tells a port in use (`AddrInUse`) apart from
+/// one that needs privileges to bind (`PermissionDenied`).
+/// ```rust
+/// use portpicker::port_bind_result;
+/// use std::io::ErrorKind;
+/// if let Err(err) = port_bind_result(80) {
+///     if err.kind() == ErrorKind::PermissionDenied {
+///         eprintln!("run as root or grant CAP_NET_BIND_SERVICE");
+///     }
+/// }
+/// ```
+pub fn port_bind_result(port: Port) -> io::Result<()> {
+    DEFAULT_BIND.probe(Protocol::Both, port)?;
+    Ok(())
+}
+
+/// Checks a port like [`port_bind_result`] on the given protocol(s), returning
+/// [`PickError::InUse`] with the protocol and address that were taken if it's
+/// busy. Other bind errors, such as missing privileges, are returned as is.
+/// ```rust
+/// use portpicker::{ensure_port_free, Protocol};
+/// if let Err(err) = ensure_port_free(8080, Protocol::Tcp) {
+///     eprintln!("can't start the server: {}", err);
+/// }
+/// ```
+pub fn ensure_port_free(port: Port, proto: Protocol) -> Result<(), PickError> {
+    let err = match DEFAULT_BIND.probe(proto, port) {
+        Ok(_) => return Ok(()),
+        Err(err) => err,
+    };
+    if !err.is_busy() || port == 0 {
+        return Err(err);
+    }
+    // Bind again one socket at a time to tell which one is taken
+    match DEFAULT_BIND.find_in_use(proto, port) {
+        Some((protocol, addr)) => Err(PickError::InUse { protocol, addr }),
+        None => Err(err),
+    }
+}
+
+/// Check if a port is free on both TCP and UDP over IPv4, ignoring IPv6
+pub fn is_free_v4(port: Port) -> bool {
+    single_family(Family::V4Only)
+        .probe(Protocol::Both, port)
+        .is_ok()
+}
+
+/// Check if a port is free on both TCP and UDP over IPv6, ignoring IPv4
+pub fn is_free_v6(port: Port) -> bool {
+    single_family(Family::V6Only)
+        .probe(Protocol::Both, port)
+        .is_ok()
 }
 
-/// Asks the OS for a free port
-fn ask_free_tcp_port() -> Option<Port> {
-    let ipv4 = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
-    let ipv6 = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0);
+// Plain binds on a single address family
+fn single_family(family: Family) -> BindOptions {
+    BindOptions {
+        family,
+        ..DEFAULT_BIND
+    }
+}
 
-    test_bind_tcp(ipv6).or_else(|| test_bind_tcp(ipv4))
+/// Check if a port is free on the given protocol(s)
+pub fn is_free_for(proto: Protocol, port: Port) -> bool {
+    DEFAULT_BIND.probe(proto, port).is_ok()
 }
 
 /// Picks an available port that is available on both TCP and UDP
+///
+/// A few random ports are tried first, after which the OS is asked to assign
+/// one. Without the `rand` feature the random phase is skipped, making this
+/// fully deterministic (OS-assigned ports only).
+///
+/// The random ports are drawn from [`DEFAULT_RANDOM_RANGE`], unless the
+/// `PORTPICKER_RANGE` environment variable is set to an inclusive `LOW-HIGH`
+/// range such as `30000-31000`. It's read once per process, and a malformed
+/// value is ignored with a warning through the `log` feature. This applies to
+/// every picker that doesn't set its own random range.
 /// ```rust
 /// use portpicker::pick_unused_port;
 /// let port: u16 = pick_unused_port().expect("No ports free");
 /// ```
 pub fn pick_unused_port() -> Option<Port> {
-    let mut rng = rand::thread_rng();
+    PortPicker::new().pick()
+}
 
-    // Try random port first
-    for _ in 0..10 {
-        let port = rng.gen_range(15000..25000);
-        if is_free(port) {
-            return Some(port);
-        }
+/// Like [`pick_unused_port`], but encodes in the type that the port is never 0
+/// ```rust
+/// use portpicker::pick_unused_port_nonzero;
+/// use std::num::NonZeroU16;
+/// let port: NonZeroU16 = pick_unused_port_nonzero().expect("No ports free");
+/// ```
+pub fn pick_unused_port_nonzero() -> Option<NonZeroU16> {
+    pick_unused_port().and_then(NonZeroU16::new)
+}
+
+/// Returns a random port from [`DEFAULT_RANDOM_RANGE`] without binding
+/// anything, so it works in sandboxes where `bind` is denied.
+///
+/// **Not for production use:** the port is never checked and may well be in
+/// use. This is only meant for unit tests of code that takes a port number
+/// without opening it. Without the `rand` feature it's always the start of the
+/// range.
+/// ```rust
+/// use portpicker::{pick_unused_port_dry_run, DEFAULT_RANDOM_RANGE};
+/// let port: u16 = pick_unused_port_dry_run();
+/// assert!(DEFAULT_RANDOM_RANGE.contains(&port));
+/// ```
+pub fn pick_unused_port_dry_run() -> Port {
+    DEFAULT_RANDOM_RANGE.start + random_offset(DEFAULT_RANDOM_RANGE.len()) as Port
+}
+
+/// Resolves a port requested on a command line such as `--port 8080`.
+///
+/// A number is returned if the port is free on both TCP and UDP, and
+/// otherwise reported as [`PickError::InUse`] by [`ensure_port_free`]. `0` or
+/// `auto` picks any free port with [`pick_unused_port`]. Anything else,
+/// including numbers above 65535, is a [`PickError::InvalidPort`].
+/// ```rust
+/// use portpicker::resolve_requested_port;
+/// let port: u16 = resolve_requested_port("auto").expect("No ports free");
+/// assert!(resolve_requested_port("70000").is_err());
+/// ```
+pub fn resolve_requested_port(spec: &str) -> Result<Port, PickError> {
+    let trimmed = spec.trim();
+    if trimmed.eq_ignore_ascii_case("auto") {
+        return pick_unused_port().ok_or(PickError::NoPortsAvailable);
     }
+    match trimmed.parse::<Port>() {
+        Ok(0) => pick_unused_port().ok_or(PickError::NoPortsAvailable),
+        Ok(port) => ensure_port_free(port, Protocol::Both).map(|_| port),
+        Err(_) => Err(PickError::InvalidPort(spec.to_owned())),
+    }
+}
 
-    // Ask the OS for a port
+/// Picks a port that is available on both TCP and UDP over IPv4, ignoring
+/// IPv6 entirely, as checked by [`is_free_v4`]
+/// ```rust
+/// use portpicker::pick_unused_port_v4;
+/// let port: u16 = pick_unused_port_v4().expect("No ports free");
+/// ```
+pub fn pick_unused_port_v4() -> Option<Port> {
+    PortPicker::new().family(Family::V4Only).pick()
+}
+
+/// Picks a port that is available on both TCP and UDP over IPv6, ignoring
+/// IPv4 entirely, as checked by [`is_free_v6`]. Returns `None` if IPv6 is
+/// unavailable.
+pub fn pick_unused_port_v6() -> Option<Port> {
+    PortPicker::new().family(Family::V6Only).pick()
+}
+
+/// Picks an available port like [`pick_unused_port`], returning the address
+/// it was found free on. With both address families available this is the
+/// unspecified IPv6 address.
+/// ```rust
+/// use portpicker::pick_unused_socket_addr;
+/// let addr = pick_unused_socket_addr().expect("No ports free");
+/// println!("free on {} over {}", addr.port(), if addr.is_ipv6() { "IPv6" } else { "IPv4" });
+/// ```
+pub fn pick_unused_socket_addr() -> Option<SocketAddr> {
+    PortPicker::new().pick_socket_addr()
+}
+
+/// Picks a port that is available on TCP, without checking UDP. This is
+/// faster than [`pick_unused_port`], and all most TCP servers need.
+/// ```rust
+/// use portpicker::pick_unused_tcp_port;
+/// let port: u16 = pick_unused_tcp_port().expect("No ports free");
+/// let listener = std::net::TcpListener::bind(("127.0.0.1", port));
+/// ```
+pub fn pick_unused_tcp_port() -> Option<Port> {
+    PortPicker::new().protocol(Protocol::Tcp).pick()
+}
+
+/// Picks an available port that is available on both TCP and UDP,
+/// reporting why if none could be found
+/// ```rust
+/// use portpicker::try_pick_unused_port;
+/// let port: u16 = try_pick_unused_port().unwrap_or_else(|err| panic!("{}", err));
+/// ```
+pub fn try_pick_unused_port() -> Result<Port, PickError> {
+    PortPicker::new().try_pick()
+}
+
+/// Picks an available port that is available on the given protocol(s)
+/// ```rust
+/// use portpicker::{pick_unused_port_for, Protocol};
+/// let port: u16 = pick_unused_port_for(Protocol::Tcp).expect("No ports free");
+/// ```
+pub fn pick_unused_port_for(proto: Protocol) -> Option<Port> {
+    PortPicker::new().protocol(proto).pick()
+}
+
+/// Picks an available port that is available on the given protocol(s),
+/// reporting why if none could be found
+pub fn try_pick_unused_port_for(proto: Protocol) -> Result<Port, PickError> {
+    PortPicker::new().protocol(proto).try_pick()
+}
+
+/// Picks a port that is available on both TCP and UDP on the given address only.
+/// Unlike [`pick_unused_port`], an IPv4 address never probes IPv6 and vice-versa.
+/// ```rust
+/// use portpicker::pick_unused_port_on;
+/// use std::net::Ipv4Addr;
+/// let port: u16 = pick_unused_port_on(Ipv4Addr::LOCALHOST.into()).expect("No ports free");
+/// ```
+pub fn pick_unused_port_on(ip: IpAddr) -> Option<Port> {
+    PortPicker::new().bind_addr(ip).pick()
+}
+
+/// Fills in a port that is available on both TCP and UDP on `template`,
+/// returning the template with that port. The address is bound exactly as
+/// given, so the scope ID and flow info of an IPv6 address are preserved.
+///
+/// The template's own port is kept if it's nonzero and free. Otherwise the OS
+/// is asked to assign a port on the template's address.
+/// ```rust
+/// use portpicker::pick_unused_port_for_addr;
+/// use std::net::SocketAddr;
+/// let template: SocketAddr = "127.0.0.1:0".parse().unwrap();
+/// let addr = pick_unused_port_for_addr(template).expect("No ports free");
+/// assert_eq!(addr.ip(), template.ip());
+/// ```
+pub fn pick_unused_port_for_addr(template: SocketAddr) -> Option<SocketAddr> {
+    // Bind the exact address, both protocols on the same port
+    let claim = |addr: SocketAddr| {
+        if addr.port() == 0 || !registry::claim(addr.port()) {
+            return false;
+        }
+        if DEFAULT_BIND.udp(addr).is_ok() {
+            return true;
+        }
+        release_port(addr.port());
+        false
+    };
+    if template.port() != 0 && DEFAULT_BIND.tcp(template).is_ok() && claim(template) {
+        return Some(template);
+    }
+    let mut addr = template;
     for _ in 0..10 {
-        if let Some(port) = ask_free_tcp_port() {
-            // Test that the udp port is free as well
-            if is_free_udp(port) {
-                return Some(port);
+        addr.set_port(0);
+        if let Ok(bound) = DEFAULT_BIND.tcp(addr) {
+            addr.set_port(bound.port());
+            if claim(addr) {
+                return Some(addr);
             }
         }
     }
+    None
+}
+
+/// Picks a port that is available on both TCP and UDP on every one of `ips`,
+/// such as the interfaces of a multi-homed host. An empty slice checks the
+/// unspecified addresses, like [`pick_unused_port`].
+/// ```rust
+/// use portpicker::pick_unused_port_on_all;
+/// use std::net::Ipv4Addr;
+/// let ips = [Ipv4Addr::LOCALHOST.into(), Ipv4Addr::UNSPECIFIED.into()];
+/// let port: u16 = pick_unused_port_on_all(&ips).expect("No ports free");
+/// ```
+pub fn pick_unused_port_on_all(ips: &[IpAddr]) -> Option<Port> {
+    PortPicker::new().bind_addrs(ips).pick()
+}
+
+/// Picks a port that is available on both TCP and UDP on every address of
+/// the host's network interfaces, as enumerated when called with the
+/// [`if-addrs`](https://crates.io/crates/if-addrs) crate. This is stricter
+/// than the unspecified addresses when sockets are bound to specific
+/// addresses. Link-local IPv6 addresses are skipped, as binding them needs a
+/// scope id. Returns `None` if the interfaces can't be enumerated.
+///
+/// Needs the `if-addrs` feature, which adds that dependency.
+/// ```rust
+/// use portpicker::pick_unused_port_all_interfaces;
+/// let port: u16 = pick_unused_port_all_interfaces().expect("No ports free");
+/// ```
+#[cfg(feature = "if-addrs")]
+pub fn pick_unused_port_all_interfaces() -> Option<Port> {
+    pick_unused_port_on_all(&interface_ips()?)
+}
+
+// Addresses of the local interfaces that can be bound without a scope id
+#[cfg(feature = "if-addrs")]
+fn interface_ips() -> Option<Vec<IpAddr>> {
+    let link_local = |ip: &IpAddr| match ip {
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+        IpAddr::V4(_) => false,
+    };
+    let interfaces = if_addrs::get_if_addrs().ok()?;
+    let ips = interfaces.iter().map(|interface| interface.ip());
+    Some(ips.filter(|ip| !link_local(ip)).collect())
+}
+
+/// Picks a port that is available on both TCP and UDP on `127.0.0.1`, for
+/// servers that only listen on the IPv4 loopback. Neither `::1` nor the
+/// unspecified addresses are probed, which makes this faster than
+/// [`pick_unused_port`].
+/// ```rust
+/// use portpicker::pick_unused_port_loopback;
+/// let port: u16 = pick_unused_port_loopback().expect("No ports free");
+/// ```
+pub fn pick_unused_port_loopback() -> Option<Port> {
+    pick_unused_port_on(Ipv4Addr::LOCALHOST.into())
+}
+
+/// Picks an available port that is available on both TCP and UDP, drawing the
+/// random candidates from the supplied RNG. A seeded RNG gives a reproducible
+/// probing order.
+/// ```rust
+/// use portpicker::pick_unused_port_with_rng;
+/// use rand::{rngs::StdRng, SeedableRng};
+/// let mut rng = StdRng::seed_from_u64(42);
+/// let port: u16 = pick_unused_port_with_rng(&mut rng).expect("No ports free");
+/// ```
+#[cfg(feature = "rand")]
+pub fn pick_unused_port_with_rng<R: Rng + ?Sized>(rng: &mut R) -> Option<Port> {
+    PortPicker::new().pick_with_rng(rng).ok()
+}
+
+/// Picks an available port that is available on both TCP and UDP and is not
+/// listed in `exclude`.
+///
+/// Excluded ports are skipped during random probing, and discarded if the OS
+/// hands one back. If the exclusions cover every port the OS is willing to
+/// assign, this gives up after the usual number of attempts and returns `None`.
+/// ```rust
+/// use portpicker::pick_unused_port_excluding;
+/// let port: u16 = pick_unused_port_excluding(&[5432, 6379]).expect("No ports free");
+/// assert!(port != 5432 && port != 6379);
+/// ```
+pub fn pick_unused_port_excluding(exclude: &[Port]) -> Option<Port> {
+    PortPicker::new().exclude(exclude).pick()
+}
+
+/// Picks an available port that is available on both TCP and UDP and lies
+/// outside `avoid`, such as ports reserved by a reverse proxy.
+///
+/// Like [`pick_unused_port_excluding`], ports in `avoid` are skipped during
+/// random probing and discarded if the OS hands one back. See
+/// [`PortPicker::avoid`] to combine it with other constraints.
+/// ```rust
+/// use portpicker::pick_unused_port_excluding_range;
+/// let port: u16 = pick_unused_port_excluding_range(20000..20100).expect("No ports free");
+/// assert!(!(20000..20100).contains(&port));
+/// ```
+pub fn pick_unused_port_excluding_range(avoid: Range<u16>) -> Option<Port> {
+    PortPicker::new().avoid(avoid).pick()
+}
+
+/// Picks an available port that is available on both TCP and UDP and is also
+/// accepted by `extra`, with the same random and OS-assigned attempts as
+/// [`pick_unused_port`]. `extra` is only called for ports that passed the
+/// built-in checks, so it can be expensive.
+/// ```rust
+/// use portpicker::pick_unused_port_where;
+/// if let Some(port) = pick_unused_port_where(|port| port % 2 == 0) {
+///     assert_eq!(port % 2, 0);
+/// }
+/// ```
+pub fn pick_unused_port_where<F: Fn(Port) -> bool>(extra: F) -> Option<Port> {
+    PortPicker::new().pick_where(extra)
+}
+
+/// Returns `preferred` if it's available on both TCP and UDP, and otherwise
+/// picks any available port like [`pick_unused_port`]
+/// ```rust
+/// use portpicker::pick_unused_port_preferred;
+/// let port: u16 = pick_unused_port_preferred(8080).expect("No ports free");
+/// ```
+pub fn pick_unused_port_preferred(preferred: Port) -> Option<Port> {
+    pick_unused_port_preferring(&[preferred])
+}
+
+/// Returns the first of `preferred` that is available on both TCP and UDP, and
+/// otherwise picks any available port like [`pick_unused_port`]
+/// ```rust
+/// use portpicker::pick_unused_port_preferring;
+/// let port: u16 = pick_unused_port_preferring(&[8080, 8000]).expect("No ports free");
+/// ```
+pub fn pick_unused_port_preferring(preferred: &[Port]) -> Option<Port> {
+    preferred
+        .iter()
+        .copied()
+        .find(|port| *port != 0 && claim_if_free(*port))
+        .or_else(pick_unused_port)
+}
+
+/// Picks an available port that is available on both TCP and UDP, drawing the
+/// random candidates from `range` instead of [`DEFAULT_RANDOM_RANGE`]. Like
+/// [`pick_unused_port`], the OS is asked to assign a port if none of them is
+/// free, so the result can lie outside `range`.
+/// ```rust
+/// use portpicker::pick_unused_port_in_random_range;
+/// let port: u16 = pick_unused_port_in_random_range(30000..31000).expect("No ports free");
+/// ```
+pub fn pick_unused_port_in_random_range(range: Range<u16>) -> Option<Port> {
+    PortPicker::new().random_range(range).pick()
+}
+
+// Try candidate ports passing `check` first, then fall back to asking the OS via `ask`
+fn pick_port<I, C, A>(
+    candidates: I,
+    check: C,
+    ask: A,
+    os_attempts: usize,
+    stats: &mut PickStats,
+) -> Result<SocketAddr, PickError>
+where
+    I: IntoIterator<Item = Port>,
+    C: Fn(Port) -> Result<SocketAddr, PickError>,
+    A: Fn() -> Result<SocketAddr, PickError>,
+{
+    let mut last_err = None;
+
+    // Try random port first
+    let random = candidates.into_iter().map(|port| {
+        stats.random_attempts += 1;
+        let attempt = check(port);
+        log_attempt("random port", port, &attempt);
+        attempt
+    });
+    if let Some(addr) = first_ok(random, &mut last_err) {
+        debug!("picked random port {}", addr);
+        return Ok(addr);
+    }
+
+    // Ask the OS for a port
+    let asked = (0..os_attempts).map(|_| {
+        stats.os_attempts += 1;
+        let attempt = ask();
+        log_attempt("OS-assigned port", 0, &attempt);
+        attempt
+    });
+    if let Some(addr) = first_ok(asked, &mut last_err) {
+        debug!("picked OS-assigned port {}", addr);
+        return Ok(addr);
+    }
 
     // Give up
+    let err = last_err.unwrap_or(PickError::NoPortsAvailable);
+    debug!("giving up: {}", err);
+    Err(err)
+}
+
+// Log the outcome of trying a single port, `port` is 0 if it wasn't known up front
+fn log_attempt(kind: &str, port: Port, attempt: &Result<SocketAddr, PickError>) {
+    match attempt {
+        Ok(addr) => debug!("{} {} is free", kind, addr),
+        Err(err) if port == 0 => debug!("{} rejected: {}", kind, err),
+        Err(err) => debug!("{} {} rejected: {}", kind, port, err),
+    }
+}
+
+// The first successful attempt, remembering errors other than a plain busy port
+fn first_ok<I, T>(attempts: I, last_err: &mut Option<PickError>) -> Option<T>
+where
+    I: IntoIterator<Item = Result<T, PickError>>,
+{
+    for attempt in attempts {
+        match attempt {
+            Ok(found) => return Some(found),
+            Err(err) if err.is_busy() => {}
+            Err(err) => *last_err = Some(err),
+        }
+    }
     None
 }
 
-/// Picks an available port that is available on both TCP and UDP within a range
+/// Picks an available port that is available on both TCP and UDP, trying
+/// `random_attempts` random ports before asking the OS up to `os_attempts`
+/// times. [`pick_unused_port`] uses 10 of each, and setting `random_attempts`
+/// to 0 skips straight to asking the OS.
 /// ```rust
-/// use portpicker::pick_unused_port_range;
-/// let port: u16 = pick_unused_port_range(15000..16000).expect("No ports free");
+/// use portpicker::pick_unused_port_with_attempts;
+/// let port: u16 = pick_unused_port_with_attempts(50, 20).expect("No ports free");
 /// ```
-pub fn pick_unused_port_range(range: Range<u16>) -> Option<Port> {
-    range
-        .into_iter()
-        .filter(|x| is_free(*x))
-        .next()
+pub fn pick_unused_port_with_attempts(random_attempts: usize, os_attempts: usize) -> Option<Port> {
+    PortPicker::new()
+        .random_attempts(random_attempts)
+        .os_attempts(os_attempts)
+        .pick()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::pick_unused_port;
-    use super::pick_unused_port_range;
+/// Picks an available port that is available on both TCP and UDP like
+/// [`pick_unused_port`], binding it once more right before returning to
+/// confirm it's still free.
+///
+/// This roughly halves the chance that another process takes the port before
+/// the caller binds it, but can't rule it out. Use [`reserve_unused_port`] or
+/// [`bind_unused_tcp`] to hold the port open instead.
+/// ```rust
+/// use portpicker::pick_unused_port_verified;
+/// let port: u16 = pick_unused_port_verified().expect("No ports free");
+/// ```
+pub fn pick_unused_port_verified() -> Option<Port> {
+    PortPicker::new().verify(true).pick()
+}
 
-    #[test]
-    fn it_works() {
-        assert!(pick_unused_port().is_some());
+/// A short settle interval for [`pick_stable_port`]
+pub const DEFAULT_SETTLE: Duration = Duration::from_millis(10);
+
+/// Picks an available port like [`pick_unused_port`] that is still free after
+/// waiting for `settle`, such as [`DEFAULT_SETTLE`], and binding it again.
+///
+/// This catches ports that were only free for a moment between another
+/// process's rapid bind and unbind cycles, at the cost of `settle` of latency
+/// per free port found.
+/// ```rust
+/// use portpicker::{pick_stable_port, DEFAULT_SETTLE};
+/// let port: u16 = pick_stable_port(DEFAULT_SETTLE).expect("No ports free");
+/// ```
+pub fn pick_stable_port(settle: Duration) -> Option<Port> {
+    PortPicker::new().settle(settle).pick()
+}
+
+/// Picks an available port that is available on both TCP and UDP, retrying
+/// like [`pick_unused_port`] until a port is found or `timeout` has elapsed.
+///
+/// The deadline is checked between attempts rather than interrupting a bind,
+/// so this can overrun `timeout` by the duration of a single bind.
+/// ```rust
+/// use portpicker::pick_unused_port_timeout;
+/// use std::time::Duration;
+/// let port: u16 = pick_unused_port_timeout(Duration::from_secs(1)).expect("No ports free");
+/// ```
+pub fn pick_unused_port_timeout(timeout: Duration) -> Option<Port> {
+    pick_until(Instant::now().checked_add(timeout))
+}
+
+/// Like [`pick_unused_port_timeout`], but retrying until an absolute
+/// `deadline`, for callers that already track an overall time budget.
+/// Returns `None` right away if `deadline` has already passed.
+/// ```rust
+/// use portpicker::pick_unused_port_by_deadline;
+/// use std::time::{Duration, Instant};
+/// let deadline = Instant::now() + Duration::from_secs(1);
+/// let port: u16 = pick_unused_port_by_deadline(deadline).expect("No ports free");
+/// ```
+pub fn pick_unused_port_by_deadline(deadline: Instant) -> Option<Port> {
+    pick_until(Some(deadline))
+}
+
+// Retry picking until a port is found or the deadline, if any, has passed
+fn pick_until(deadline: Option<Instant>) -> Option<Port> {
+    let mut picker = PortPicker::new();
+    if let Some(deadline) = deadline {
+        picker = picker.deadline(deadline);
+    }
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return None;
+        }
+        if let Some(port) = picker.pick() {
+            return Some(port);
+        }
     }
+}
 
-    #[test]
-    fn port_range_test(){
-        if let Some(p) = pick_unused_port_range(15000..16000) {
-            assert!(p >= 15000 && p <= 16000)
+/// Picks an available port that is available on both TCP and UDP using the
+/// given search strategy. [`pick_unused_port`] uses [`Strategy::RandomFirst`].
+/// ```rust
+/// use portpicker::{pick_unused_port_strategy, Strategy};
+/// let port: u16 = pick_unused_port_strategy(Strategy::OsOnly).expect("No ports free");
+/// ```
+pub fn pick_unused_port_strategy(strategy: Strategy) -> Option<Port> {
+    PortPicker::new().strategy(strategy).pick()
+}
+
+/// How many attempts a pick took, as reported by [`pick_unused_port_with_stats`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct PickStats {
+    /// Ports probed before asking the OS, including those of a range scan
+    pub random_attempts: usize,
+    /// Times the OS was asked to assign a port
+    pub os_attempts: usize,
+}
+
+/// Picks an available port like [`pick_unused_port`], also reporting how many
+/// attempts it took. Many attempts hint at a contended port space.
+/// ```rust
+/// use portpicker::pick_unused_port_with_stats;
+/// let (port, stats) = pick_unused_port_with_stats().expect("No ports free");
+/// println!("{} after {} random attempts", port, stats.random_attempts);
+/// ```
+pub fn pick_unused_port_with_stats() -> Option<(Port, PickStats)> {
+    PortPicker::new().pick_with_stats()
+}
+
+/// Where a picked port came from, as reported by
+/// [`pick_unused_port_with_source`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    /// One of the probed candidates, drawn from the random range or the
+    /// picker's [`range`](PortPicker::range)
+    RandomInRange,
+    /// Assigned by the OS after no candidate was free, so it can lie outside
+    /// the random range
+    OsAssigned,
+}
+
+impl PickStats {
+    /// Where the picked port came from. The OS is only asked once every
+    /// candidate was rejected, so any OS attempt means the port is OS-assigned.
+    pub fn source(&self) -> Source {
+        if self.os_attempts > 0 {
+            Source::OsAssigned
+        } else {
+            Source::RandomInRange
         }
-        if let Some(p) = pick_unused_port_range(20000..21000) {
-            assert!(p >= 20000 && p <= 21000)
+    }
+}
+
+/// Picks an available port like [`pick_unused_port`], also reporting whether
+/// it was a random candidate or assigned by the OS. An OS-assigned port is
+/// usually from the ephemeral range and may not be covered by firewall rules.
+/// ```rust
+/// use portpicker::{pick_unused_port_with_source, Source};
+/// let (port, source) = pick_unused_port_with_source().expect("No ports free");
+/// if source == Source::OsAssigned {
+///     println!("{} is outside the usual range", port);
+/// }
+/// ```
+pub fn pick_unused_port_with_source() -> Option<(Port, Source)> {
+    let (port, stats) = PortPicker::new().pick_with_stats()?;
+    Some((port, stats.source()))
+}
+
+/// Picks `n` distinct ports that are each available on both TCP and UDP,
+/// or `None` if that many could not be found
+/// ```rust
+/// use portpicker::pick_n_unused_ports;
+/// let ports: Vec<u16> = pick_n_unused_ports(5).expect("No ports free");
+/// assert_eq!(ports.len(), 5);
+/// ```
+pub fn pick_n_unused_ports(n: usize) -> Option<Vec<Port>> {
+    PortPicker::new().pick_n(n)
+}
+
+/// Picks a distinct free port for each of `names`, such as the services of a
+/// test fixture, like [`pick_n_unused_ports`]. A name listed twice gets a
+/// single port. Returns `None` if not every name could get a port.
+/// ```rust
+/// use portpicker::pick_named_ports;
+/// let ports = pick_named_ports(&["http", "grpc", "metrics"]).expect("No ports free");
+/// let http: u16 = ports["http"];
+/// ```
+pub fn pick_named_ports(names: &[&str]) -> Option<HashMap<String, Port>> {
+    let mut unique: Vec<&str> = Vec::with_capacity(names.len());
+    for name in names {
+        if !unique.contains(name) {
+            unique.push(name);
+        }
+    }
+    let ports = pick_n_unused_ports(unique.len())?;
+    let named = unique.into_iter().map(str::to_owned).zip(ports);
+    Some(named.collect())
+}
+
+/// Picks an available port like [`pick_unused_port`] and writes it, followed
+/// by a newline, to the file at `path` for another process to read.
+///
+/// The port is written to a temporary file next to `path` which is then
+/// renamed over it, so a reader never sees a partially written file. Returns
+/// `None` if no port is free or the file can't be written.
+/// ```rust,no_run
+/// use portpicker::pick_and_write_port;
+/// use std::path::Path;
+/// let port: u16 = pick_and_write_port(Path::new("server.port")).expect("No ports free");
+/// ```
+pub fn pick_and_write_port(path: &Path) -> Option<Port> {
+    // Tells apart the temporary files of threads writing the same path
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+    let port = pick_unused_port()?;
+    let write = WRITES.fetch_add(1, Ordering::Relaxed);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.{}.tmp", std::process::id(), write));
+    let written = fs::write(&tmp, format!("{}\n", port)).and_then(|_| fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+        release_port(port);
+        return None;
+    }
+    Some(port)
+}
+
+/// Picks an available port and keeps it bound on both TCP and UDP until the
+/// returned guard is dropped, closing the race between picking and binding.
+///
+/// The caller must rebind with the reuse options described in [`PortGuard`].
+/// ```rust
+/// use portpicker::reserve_unused_port;
+/// let guard = reserve_unused_port().expect("No ports free");
+/// let port: u16 = guard.port();
+/// ```
+pub fn reserve_unused_port() -> Option<PortGuard> {
+    for _ in 0..10 {
+        let port = pick_unused_port()?;
+        match PortGuard::bind(port) {
+            Ok(guard) => return Some(guard),
+            Err(_) => release_port(port),
         }
     }
+    None
+}
+
+/// Like [`pick_unused_port`], with the result typed as
+/// [`PortAvailability::Probed`], as nothing stops another process from taking
+/// the port before it's used
+/// ```rust
+/// use portpicker::probe_unused_port;
+/// let port: u16 = probe_unused_port().expect("No ports free").port();
+/// ```
+pub fn probe_unused_port() -> Option<PortAvailability> {
+    pick_unused_port().map(PortAvailability::Probed)
+}
+
+/// Like [`reserve_unused_port`], with the result typed as
+/// [`PortAvailability::Held`]
+/// ```rust
+/// use portpicker::hold_unused_port;
+/// let held = hold_unused_port().expect("No ports free");
+/// assert!(held.is_held());
+/// ```
+pub fn hold_unused_port() -> Option<PortAvailability> {
+    reserve_unused_port().map(PortAvailability::Held)
+}
+
+/// Binds a TCP listener on an OS-assigned free port and returns it still open,
+/// so there is no race between picking the port and using it.
+///
+/// The listener is bound to the unspecified IPv6 address (which also accepts
+/// IPv4 on dual-stack hosts), or the unspecified IPv4 address if IPv6 is
+/// unavailable.
+/// ```rust
+/// use portpicker::bind_unused_tcp;
+/// let (port, listener) = bind_unused_tcp().expect("No ports free");
+/// assert_eq!(listener.local_addr().unwrap().port(), port);
+/// ```
+pub fn bind_unused_tcp() -> Option<(Port, TcpListener)> {
+    (0..10).find_map(|_| {
+        let listener = TcpListener::bind(SocketAddr::new(unspecified(), 0)).ok()?;
+        let port = listener.local_addr().ok()?.port();
+        Some((port, listener)).filter(|_| port != 0)
+    })
+}
+
+/// Like [`bind_unused_tcp`], but with the listener already set to nonblocking
+/// mode for event loops such as mio. The listener is bound to the same
+/// unspecified address, and dropping it frees the port. See
+/// [`bind_unused_tcp_on`] to bind a specific address instead.
+/// ```rust
+/// use portpicker::bind_unused_tcp_nonblocking;
+/// let (port, listener) = bind_unused_tcp_nonblocking().expect("No ports free");
+/// ```
+pub fn bind_unused_tcp_nonblocking() -> Option<(Port, TcpListener)> {
+    let (port, listener) = bind_unused_tcp()?;
+    listener.set_nonblocking(true).ok()?;
+    Some((port, listener))
+}
+
+/// Binds a TCP listener on an OS-assigned free port like [`bind_unused_tcp`],
+/// and returns its raw fd with `FD_CLOEXEC` cleared, so that a child process
+/// can inherit the bound socket, in the style of systemd socket activation.
+///
+/// The caller is responsible for passing the fd number to the child and for
+/// closing it, for example by adopting it with `TcpListener::from_raw_fd`.
+/// The port stays taken until every process holding the fd has closed it.
+/// ```rust
+/// use portpicker::reserve_unused_tcp_inheritable;
+/// use std::net::TcpListener;
+/// use std::os::unix::io::FromRawFd;
+/// let (port, fd) = reserve_unused_tcp_inheritable().expect("No ports free");
+/// // Once the child has been spawned, close our copy
+/// drop(unsafe { TcpListener::from_raw_fd(fd) });
+/// ```
+#[cfg(unix)]
+pub fn reserve_unused_tcp_inheritable() -> Option<(Port, std::os::unix::io::RawFd)> {
+    use std::os::unix::io::IntoRawFd;
+
+    let (port, listener) = bind_unused_tcp()?;
+    let socket = Socket::from(listener);
+    socket.set_cloexec(false).ok()?;
+    Some((port, socket.into_raw_fd()))
+}
+
+/// Binds a TCP listener on an OS-assigned free port of exactly `ip` and
+/// returns it still open. An IPv6 address is bound with `IPV6_V6ONLY` set, so
+/// `::` doesn't also take the port on IPv4. Returns `None` if the bind fails.
+///
+/// An `IpAddr` has no scope ID, so use [`bind_unused_tcp_at`] for link-local
+/// IPv6 addresses.
+/// ```rust
+/// use portpicker::bind_unused_tcp_on;
+/// use std::net::Ipv4Addr;
+/// let (port, listener) = bind_unused_tcp_on(Ipv4Addr::LOCALHOST.into()).expect("No ports free");
+/// ```
+pub fn bind_unused_tcp_on(ip: IpAddr) -> Option<(Port, TcpListener)> {
+    bind_unused_tcp_at(SocketAddr::new(ip, 0))
+}
+
+/// Like [`bind_unused_tcp_on`], but binds the IP of `addr` with its scope ID
+/// and flow info, as needed for link-local IPv6 addresses. The port of `addr`
+/// is ignored in favor of an OS-assigned one.
+/// ```rust
+/// use portpicker::bind_unused_tcp_at;
+/// use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+/// let addr = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 0, 0);
+/// if let Some((port, listener)) = bind_unused_tcp_at(SocketAddr::V6(addr)) {
+///     println!("listening on {}", port);
+/// }
+/// ```
+pub fn bind_unused_tcp_at(mut addr: SocketAddr) -> Option<(Port, TcpListener)> {
+    addr.set_port(0);
+    (0..10).find_map(|_| {
+        let listener = listen_exactly(addr)?;
+        let port = listener.local_addr().ok()?.port();
+        Some((port, listener)).filter(|_| port != 0)
+    })
+}
+
+/// Binds an IPv4 and an IPv6 TCP listener on the same OS-assigned free port
+/// and returns both still open, as `(port, v4, v6)`, for servers that listen
+/// on each family with a separate socket like nginx does.
+///
+/// The listeners are bound to the unspecified addresses, with `IPV6_V6ONLY`
+/// set on the IPv6 one so that they don't conflict. If the IPv4 bind fails
+/// on the port the OS assigned for IPv6, both are closed and another port
+/// is tried. Returns `None` if IPv6 is unavailable.
+/// ```rust
+/// use portpicker::bind_unused_tcp_both_families;
+/// if let Some((port, v4, v6)) = bind_unused_tcp_both_families() {
+///     assert!(v4.local_addr().unwrap().is_ipv4() && v6.local_addr().unwrap().is_ipv6());
+/// }
+/// ```
+pub fn bind_unused_tcp_both_families() -> Option<(Port, TcpListener, TcpListener)> {
+    (0..10).find_map(|_| {
+        let (port, v6) = bind_unused_tcp_at(SocketAddr::new(UNSPECIFIED_V6, 0))?;
+        let v4 = listen_exactly(SocketAddr::new(UNSPECIFIED_V4, port))?;
+        Some((port, v4, v6))
+    })
+}
+
+// Listen on exactly `addr`, only on IPv6 for an IPv6 address
+fn listen_exactly(addr: SocketAddr) -> Option<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None).ok()?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true).ok()?;
+    }
+    // Like std's bind
+    #[cfg(unix)]
+    socket.set_reuse_address(true).ok()?;
+    socket.bind(&addr.into()).ok()?;
+    socket.listen(128).ok()?;
+    Some(TcpListener::from(socket))
+}
+
+/// Binds a UDP socket on an OS-assigned free port and returns it still open.
+/// The UDP analog of [`bind_unused_tcp`].
+/// ```rust
+/// use portpicker::bind_unused_udp;
+/// let (port, socket) = bind_unused_udp().expect("No ports free");
+/// assert_eq!(socket.local_addr().unwrap().port(), port);
+/// ```
+pub fn bind_unused_udp() -> Option<(Port, UdpSocket)> {
+    (0..10).find_map(|_| {
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified(), 0)).ok()?;
+        let port = socket.local_addr().ok()?.port();
+        Some((port, socket)).filter(|_| port != 0)
+    })
+}
+
+/// Binds a TCP listener and a UDP socket on the same free port and returns
+/// them still open, for servers that need one port number on both protocols.
+///
+/// The OS assigns a TCP port, and the UDP socket is bound on it while the
+/// listener is still held, so no other process can take the TCP port in
+/// between. If the UDP bind fails, a different port is tried. The sockets are
+/// bound like in [`bind_unused_tcp`].
+/// ```rust
+/// use portpicker::bind_unused_dual;
+/// let (port, listener, socket) = bind_unused_dual().expect("No ports free");
+/// assert_eq!(listener.local_addr().unwrap().port(), port);
+/// assert_eq!(socket.local_addr().unwrap().port(), port);
+/// ```
+pub fn bind_unused_dual() -> Option<(Port, TcpListener, UdpSocket)> {
+    (0..10).find_map(|_| {
+        let listener = TcpListener::bind(SocketAddr::new(unspecified(), 0)).ok()?;
+        let addr = listener.local_addr().ok()?;
+        if addr.port() == 0 {
+            return None;
+        }
+        let socket = UdpSocket::bind(addr).ok()?;
+        Some((addr.port(), listener, socket))
+    })
+}
+
+/// Binds both a TCP listener and a UDP socket on `port`, returning them only
+/// if both binds succeed. Unlike [`is_free`], whose probe sockets are closed
+/// one after the other, the port is then held on both protocols at once
+/// until the sockets are dropped. If only one bind succeeds, its socket is
+/// closed again. The sockets are bound like in [`bind_unused_tcp`], and
+/// port 0 is rejected.
+/// ```rust
+/// use portpicker::{pick_unused_port, try_hold_tcp_udp};
+/// let port = pick_unused_port().expect("No ports free");
+/// if let Some((listener, socket)) = try_hold_tcp_udp(port) {
+///     assert_eq!(socket.local_addr().unwrap().port(), port);
+/// }
+/// ```
+pub fn try_hold_tcp_udp(port: Port) -> Option<(TcpListener, UdpSocket)> {
+    if port == 0 {
+        return None;
+    }
+    let addr = SocketAddr::new(unspecified(), port);
+    let listener = TcpListener::bind(addr).ok()?;
+    let socket = UdpSocket::bind(addr).ok()?;
+    Some((listener, socket))
+}
+
+// The unspecified IPv6 address, or IPv4 if IPv6 is unavailable
+fn unspecified() -> IpAddr {
+    if ipv6_available() {
+        UNSPECIFIED_V6
+    } else {
+        UNSPECIFIED_V4
+    }
+}
+
+/// Picks an available port that is available on both TCP and UDP within a range
+/// ```rust
+/// use portpicker::pick_unused_port_range;
+/// let port: u16 = pick_unused_port_range(15000..16000).expect("No ports free");
+/// ```
+///
+/// A few random ports within the range are tried first, as in
+/// [`pick_unused_port`], so that concurrent callers spread out. Only then is
+/// the whole range scanned, starting at a random offset and wrapping around.
+/// See [`lowest_free_port`] for a deterministic scan.
+pub fn pick_unused_port_range(range: Range<u16>) -> Option<Port> {
+    PortPicker::new().range(range).pick()
+}
+
+/// Picks an available port that is available on both TCP and UDP within a
+/// range, reporting why if none could be found. An empty or reversed range
+/// fails with [`PickError::EmptyRange`] rather than looking fully busy.
+/// ```rust
+/// use portpicker::{try_pick_unused_port_range, PickError};
+/// let port: u16 = try_pick_unused_port_range(15000..16000).expect("No ports free");
+/// let empty = try_pick_unused_port_range(15000..15000);
+/// assert!(matches!(empty, Err(PickError::EmptyRange)));
+/// ```
+pub fn try_pick_unused_port_range(range: Range<u16>) -> Result<Port, PickError> {
+    PortPicker::new().range(range).try_pick()
+}
+
+/// Picks an available port that is available on both TCP and UDP within an
+/// inclusive range, so that the upper bound itself can be returned
+/// ```rust
+/// use portpicker::pick_unused_port_range_inclusive;
+/// let port: u16 = pick_unused_port_range_inclusive(15000..=16000).expect("No ports free");
+/// ```
+pub fn pick_unused_port_range_inclusive(range: RangeInclusive<u16>) -> Option<Port> {
+    PortPicker::new().range_inclusive(range).pick()
+}
+
+/// Like [`pick_unused_port_range`], but checks the ports on the rayon thread
+/// pool, which is much faster for wide ranges
+/// ```rust
+/// use portpicker::pick_unused_port_range_parallel;
+/// let port: u16 = pick_unused_port_range_parallel(15000..65000).expect("No ports free");
+/// ```
+///
+/// Any port found free is checked again once the parallel scan has finished,
+/// and the sequential scan is used if it turned out to be busy after all.
+#[cfg(feature = "rayon")]
+pub fn pick_unused_port_range_parallel(range: Range<u16>) -> Option<Port> {
+    use rayon::prelude::*;
+
+    let found = range
+        .clone()
+        .into_par_iter()
+        .find_any(|port| !registry::is_claimed(*port) && is_free(*port));
+    match found {
+        Some(port) if claim_if_free(port) => return Some(port),
+        Some(_) => {}
+        None => return None,
+    }
+    pick_unused_port_range(range)
+}
+
+// Number of sub-windows a range is split into by `pick_unused_port_sharded`
+const SHARDS: usize = 64;
+
+/// Picks an available port that is available on both TCP and UDP within a
+/// range, starting in a sub-window of the range derived from `shard_seed`.
+///
+/// Processes picking from the same range at once, such as parallel CI jobs
+/// passing their [`std::process::id`], then mostly probe different ports.
+/// This is a heuristic that reduces collisions between them rather than
+/// ruling them out: seeds can map to the same window, and once its window is
+/// exhausted the rest of the range is scanned, wrapping around.
+/// ```rust
+/// use portpicker::pick_unused_port_sharded;
+/// let seed = u64::from(std::process::id());
+/// let port: u16 = pick_unused_port_sharded(15000..16000, seed).expect("No ports free");
+/// ```
+pub fn pick_unused_port_sharded(range: Range<u16>, shard_seed: u64) -> Option<Port> {
+    let len = range.len();
+    if len == 0 {
+        return None;
+    }
+    let window = (len + SHARDS - 1) / SHARDS;
+    let mixed = mix_seed(shard_seed);
+    let shard = (mixed % SHARDS as u64) as usize;
+    // Start at a seed-dependent port within the window, so that seeds sharing
+    // a window don't probe it in lockstep either
+    let offset = (shard * window + ((mixed >> 32) % window as u64) as usize) % len;
+    wrapping_scan(range.start, len, offset).find(|port| claim_if_free(*port))
+}
+
+// Spread nearby seeds such as consecutive PIDs over the shards (splitmix64)
+fn mix_seed(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Picks an available port that is available on both TCP and UDP within any of
+/// several ranges
+/// ```rust
+/// use portpicker::pick_unused_port_in_ranges;
+/// let port: u16 = pick_unused_port_in_ranges(&[15000..16000, 30000..31000])
+///     .expect("No ports free");
+/// ```
+///
+/// The ports of all ranges are shuffled together, so that no range is always
+/// exhausted first, and ports in overlapping ranges are only checked once.
+/// Without the `rand` feature they are tried in ascending order.
+pub fn pick_unused_port_in_ranges(ranges: &[Range<u16>]) -> Option<Port> {
+    pick_first_free_shuffled(ranges.iter().cloned().flatten().collect())
+}
+
+// Hand out the first free port of `ports` after removing duplicates and
+// shuffling them, or in ascending order without `rand`
+fn pick_first_free_shuffled(mut ports: Vec<Port>) -> Option<Port> {
+    ports.sort_unstable();
+    ports.dedup();
+    #[cfg(feature = "rand")]
+    ports.shuffle(&mut thread_rng());
+    ports.into_iter().find(|port| claim_if_free(*port))
+}
+
+// Hand out `port` if it's free on both TCP and UDP and not already handed out
+pub(crate) fn claim_if_free(port: Port) -> bool {
+    claim_if(port, is_free)
+}
+
+// Hand out `port` if `free` accepts it and it's not already handed out
+fn claim_if(port: Port, free: fn(Port) -> bool) -> bool {
+    if !registry::claim(port) {
+        return false;
+    }
+    if free(port) {
+        return true;
+    }
+    release_port(port);
+    false
+}
+
+// Hand out the `count` ports from `first` if `free` accepts each of them, or
+// none of them
+fn claim_all(first: Port, count: usize, free: fn(Port) -> bool) -> bool {
+    let ports = || (0..count).map(|i| first + i as Port);
+    match ports().position(|port| !claim_if(port, free)) {
+        Some(busy) => {
+            ports().take(busy).for_each(release_port);
+            false
+        }
+        None => true,
+    }
+}
+
+// A uniformly random offset below `len`, or 0 without `rand`
+pub(crate) fn random_offset(len: usize) -> usize {
+    #[cfg(feature = "rand")]
+    if len > 0 {
+        return thread_rng().gen_range(0..len);
+    }
+    let _ = len;
+    0
+}
+
+/// Picks `count` consecutive ports that are all available on both TCP and
+/// UDP, such as a passive FTP data range. Ports below 1024 are never used, and
+/// the search starts at a random port so that concurrent callers spread out.
+/// Returns `None` for a `count` of 0 or if no such block is free.
+/// ```rust
+/// use portpicker::pick_unused_port_block;
+/// let block = pick_unused_port_block(4).expect("No ports free");
+/// assert_eq!(block.len(), 4);
+/// ```
+pub fn pick_unused_port_block(count: usize) -> Option<Range<u16>> {
+    const FIRST: usize = 1024;
+    // The block must end at `Port::MAX` at the latest, as its end is exclusive
+    let starts = (Port::MAX as usize + 1).checked_sub(FIRST + count)?;
+    if count == 0 || starts == 0 {
+        return None;
+    }
+    wrapping_scan(FIRST as Port, starts, random_offset(starts))
+        .find(|&start| claim_all(start, count, is_free))
+        .map(|start| start..start + count as Port)
+}
+
+/// Like [`pick_unused_port_block`], but keeps every port of the block bound
+/// with a [`PortGuard`], so that the whole block stays reserved until the
+/// guards are dropped. If any port of a block can't be held, the ports
+/// already held are released and another block is tried, up to 10 times.
+/// ```rust
+/// use portpicker::reserve_unused_block;
+/// let guards = reserve_unused_block(4).expect("No ports free");
+/// let first = guards[0].port();
+/// assert!(guards.iter().zip(first..).all(|(guard, port)| guard.port() == port));
+/// ```
+pub fn reserve_unused_block(count: usize) -> Option<Vec<PortGuard>> {
+    for _ in 0..10 {
+        let block = pick_unused_port_block(count)?;
+        let mut guards = Vec::with_capacity(count);
+        for port in block.clone() {
+            match PortGuard::bind(port) {
+                Ok(guard) => guards.push(guard),
+                Err(_) => break,
+            }
+        }
+        if guards.len() == count {
+            return Some(guards);
+        }
+        // Dropping the guards releases the ports they held
+        let held = guards.len();
+        drop(guards);
+        block.skip(held).for_each(release_port);
+    }
+    None
+}
+
+/// Picks an RTP/RTCP pair of ports `(even, even + 1)`, both available on UDP.
+/// Ports below 1024 are never used.
+/// ```rust
+/// use portpicker::pick_rtp_port_pair;
+/// let (rtp, rtcp) = pick_rtp_port_pair().expect("No ports free");
+/// assert!(rtp % 2 == 0 && rtcp == rtp + 1);
+/// ```
+pub fn pick_rtp_port_pair() -> Option<(Port, Port)> {
+    let pairs = (Port::MAX as usize + 1 - 1024) / 2;
+    wrapping_scan(0, pairs, random_offset(pairs))
+        .map(|pair| 1024 + 2 * pair)
+        .find(|&rtp| claim_all(rtp, 2, is_free_udp))
+        .map(|rtp| (rtp, rtp + 1))
+}
+
+/// Picks the lowest port in a range that is available on both TCP and UDP
+/// ```rust
+/// use portpicker::lowest_free_port;
+/// let port: u16 = lowest_free_port(15000..16000).expect("No ports free");
+/// ```
+pub fn lowest_free_port(range: Range<u16>) -> Option<Port> {
+    range.into_iter().find(|x| claim_if_free(*x))
+}
+
+/// Picks the highest port in a range that is available on both TCP and UDP
+/// ```rust
+/// use portpicker::highest_free_port;
+/// let port: u16 = highest_free_port(15000..16000).expect("No ports free");
+/// ```
+pub fn highest_free_port(range: Range<u16>) -> Option<Port> {
+    range.rev().find(|x| claim_if_free(*x))
+}
+
+/// Picks the first port from `start` upwards that is available on both TCP
+/// and UDP, as dev servers do when their usual port is busy. The scan stops
+/// at 65535 rather than wrapping around to lower ports.
+/// ```rust
+/// use portpicker::first_free_port_from;
+/// let port: u16 = first_free_port_from(3000).expect("No ports free");
+/// assert!(port >= 3000);
+/// ```
+pub fn first_free_port_from(start: Port) -> Option<Port> {
+    (start..=Port::MAX).find(|x| claim_if_free(*x))
+}
+
+/// Like [`first_free_port_from`], but scanning downwards from `start` to 1
+pub fn first_free_port_down_from(start: Port) -> Option<Port> {
+    (1..=start).rev().find(|x| claim_if_free(*x))
+}
+
+/// Picks the first port available on both TCP and UDP among every `step`th
+/// port of a range, counting from `range.start`. With `descending` the
+/// candidates are scanned from the highest one down, so `step = 2` over
+/// `20000..21000` gives the highest free even port. A `step` of 0 returns
+/// `None`.
+/// ```rust
+/// use portpicker::pick_unused_port_stepped;
+/// let port: u16 = pick_unused_port_stepped(20000..21000, 2, true).expect("No ports free");
+/// assert!(port % 2 == 0);
+/// ```
+pub fn pick_unused_port_stepped(range: Range<u16>, step: u16, descending: bool) -> Option<Port> {
+    if step == 0 {
+        return None;
+    }
+    let mut candidates = range.step_by(step.into());
+    if descending {
+        candidates.rev().find(|port| claim_if_free(*port))
+    } else {
+        candidates.find(|port| claim_if_free(*port))
+    }
+}
+
+/// Lazily yields the ports in a range that are available on both TCP and UDP,
+/// in ascending order
+/// ```rust
+/// use portpicker::free_ports;
+/// let ports: Vec<u16> = free_ports(15000..16000).take(3).collect();
+/// ```
+///
+/// Each port is checked only when the iterator reaches it, and no socket is
+/// kept open between calls to `next`. A yielded port may be taken by another
+/// process the instant after it was checked, so bind it promptly.
+pub fn free_ports(range: Range<u16>) -> impl Iterator<Item = Port> {
+    range.filter(|port| is_free(*port))
+}
+
+/// Counts the ports in a range that are available on both TCP and UDP.
+///
+/// This is a best-effort snapshot: ports are checked one after another and can
+/// be taken or released by other processes at any time, even during the count.
+/// ```rust
+/// use portpicker::count_free_ports;
+/// let free: usize = count_free_ports(15000..15100);
+/// ```
+pub fn count_free_ports(range: Range<u16>) -> usize {
+    free_ports(range).count()
+}
+
+/// Check if any port in a range is available on both TCP and UDP, stopping at
+/// the first free one
+/// ```rust
+/// use portpicker::contains_free_port;
+/// assert!(contains_free_port(15000..16000));
+/// ```
+pub fn contains_free_port(range: Range<u16>) -> bool {
+    free_ports(range).next().is_some()
+}
+
+// Every port in `start..start + len` once, starting `offset` ports in and wrapping around
+pub(crate) fn wrapping_scan(start: Port, len: usize, offset: usize) -> impl Iterator<Item = Port> {
+    (0..len).map(move |i| start + ((offset + i) % len) as Port)
+}
+
+// Number of ports in an inclusive range
+pub(crate) fn inclusive_len(range: &RangeInclusive<u16>) -> usize {
+    if range.is_empty() {
+        0
+    } else {
+        (*range.end() - *range.start()) as usize + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ipv6_available;
+    use super::is_free_tcp_reuse;
+    use super::pick_n_unused_ports;
+    use super::pick_unused_port;
+    use super::pick_unused_port_excluding;
+    use super::pick_unused_port_for;
+    use super::pick_unused_port_range;
+    use super::pick_unused_port_range_inclusive;
+    use super::pick_unused_port_with_attempts;
+    use super::release_port;
+    use super::PortPicker;
+    use super::{bind_unused_dual, bind_unused_tcp, bind_unused_udp};
+    use super::{free_ports, highest_free_port, lowest_free_port, wrapping_scan};
+    use super::{is_free, reserve_unused_port};
+    use super::{is_free_on, pick_unused_port_on};
+    use super::{is_free_tcp, is_free_udp, Protocol};
+    use super::{try_pick_unused_port, PickError};
+    use std::net::Ipv6Addr;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    #[test]
+    fn it_works() {
+        assert!(pick_unused_port().is_some());
+    }
+
+    #[test]
+    #[allow(clippy::manual_range_contains)]
+    fn port_range_test() {
+        if let Some(p) = pick_unused_port_range(15000..16000) {
+            assert!(p >= 15000 && p < 16000)
+        }
+        if let Some(p) = pick_unused_port_range(20000..21000) {
+            assert!(p >= 20000 && p < 21000)
+        }
+    }
+
+    #[test]
+    fn protocol_test() {
+        let p = pick_unused_port_for(Protocol::Tcp).expect("No TCP ports free");
+        assert!(is_free_tcp(p));
+        let p = pick_unused_port_for(Protocol::Udp).expect("No UDP ports free");
+        assert!(is_free_udp(p));
+    }
+
+    #[test]
+    fn bind_addr_test() {
+        let ip = Ipv4Addr::LOCALHOST.into();
+        let p = pick_unused_port_on(ip).expect("No ports free on loopback");
+        assert!(is_free_on(ip, p));
+        let _listener = TcpListener::bind((Ipv4Addr::LOCALHOST, p)).unwrap();
+        assert!(!is_free_on(ip, p));
+    }
+
+    #[test]
+    fn guard_test() {
+        let guard = reserve_unused_port().expect("No ports free");
+        let port = guard.port();
+        assert!(!is_free(port));
+        assert!(!is_free_tcp(port));
+        assert!(TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).is_err());
+        if ipv6_available() {
+            assert!(TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).is_err());
+        }
+        // Rebinding with the guard's options works
+        #[cfg(target_os = "linux")]
+        {
+            use socket2::{Domain, Socket, Type};
+            use std::net::SocketAddr;
+
+            let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+            let tcp = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+            tcp.set_reuse_port(true).unwrap();
+            tcp.bind(&addr.into()).unwrap();
+            tcp.listen(128).unwrap();
+            let udp = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+            udp.set_reuse_address(true).unwrap();
+            udp.bind(&addr.into()).unwrap();
+        }
+        let (listener, _udp) = guard.into_inner().unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+        drop(listener);
+    }
+
+    #[test]
+    fn error_test() {
+        assert!(try_pick_unused_port().is_ok());
+        let err = PickError::NoPortsAvailable;
+        assert_eq!(err.to_string(), "no free ports available");
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn seeded_rng_test() {
+        use super::pick_unused_port_with_rng;
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let a = pick_unused_port_with_rng(&mut StdRng::seed_from_u64(42));
+        assert!(a.is_some());
+        release_port(a.unwrap());
+        let b = pick_unused_port_with_rng(&mut StdRng::seed_from_u64(42));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn wrapping_scan_test() {
+        let ports: Vec<u16> = wrapping_scan(100, 5, 3).collect();
+        assert_eq!(ports, vec![103, 104, 100, 101, 102]);
+        assert_eq!(wrapping_scan(100, 0, 0).count(), 0);
+        assert_eq!(lowest_free_port(100..100), None);
+    }
+
+    #[test]
+    fn inclusive_range_test() {
+        assert_eq!(pick_unused_port_range(65535..65535), None);
+        let expected = if is_free(65535) { Some(65535) } else { None };
+        assert_eq!(pick_unused_port_range_inclusive(65535..=65535), expected);
+        if let Some(p) = pick_unused_port_range_inclusive(15000..=15001) {
+            assert!((15000..=15001).contains(&p))
+        }
+    }
+
+    #[test]
+    fn exclude_test() {
+        let taken = pick_unused_port().expect("No ports free");
+        for _ in 0..10 {
+            let p = pick_unused_port_excluding(&[taken]).expect("No ports free");
+            assert_ne!(p, taken);
+        }
+    }
+
+    #[test]
+    fn builder_test() {
+        let p = PortPicker::new()
+            .protocol(Protocol::Tcp)
+            .range(15000..15100)
+            .exclude(&[15000, 15001])
+            .bind_addr(Ipv4Addr::LOCALHOST.into())
+            .pick()
+            .expect("No ports free");
+        assert!((15002..15100).contains(&p));
+        assert!(PortPicker::new().range(20000..20000).pick().is_none());
+    }
+
+    #[test]
+    fn pick_n_test() {
+        let mut ports = pick_n_unused_ports(20).expect("No ports free");
+        ports.sort_unstable();
+        ports.dedup();
+        assert_eq!(ports.len(), 20);
+        assert!(PortPicker::new().range(15000..15002).pick_n(3).is_none());
+    }
+
+    #[test]
+    fn ipv6_detection_test() {
+        let v6 = TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)).is_ok();
+        assert_eq!(ipv6_available(), v6);
+        let port = pick_unused_port_for(Protocol::Tcp).expect("No ports free");
+        assert!(is_free_tcp(port));
+    }
+
+    #[test]
+    fn bind_unused_test() {
+        let (port, _listener) = bind_unused_tcp().expect("No ports free");
+        assert!(!is_free_tcp(port));
+        let (port, _socket) = bind_unused_udp().expect("No ports free");
+        assert!(!is_free_udp(port));
+    }
+
+    #[test]
+    fn attempts_test() {
+        assert!(pick_unused_port_with_attempts(0, 10).is_some());
+        assert!(pick_unused_port_with_attempts(0, 0).is_none());
+    }
+
+    #[test]
+    fn reuse_addr_test() {
+        let port = PortPicker::new()
+            .protocol(Protocol::Tcp)
+            .reuse_addr(true)
+            .pick()
+            .expect("No ports free");
+        assert!(is_free_tcp_reuse(port));
+        let _listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).unwrap();
+        assert!(!is_free_tcp_reuse(port));
+    }
+
+    #[test]
+    fn registry_test() {
+        let port = PortPicker::new()
+            .range(24000..24002)
+            .pick()
+            .expect("No ports free");
+        let other = PortPicker::new().range(24000..24002).pick();
+        assert_ne!(other, Some(port));
+        release_port(port);
+        if let Some(other) = other {
+            release_port(other);
+        }
+        let again = PortPicker::new().range(port..port + 1).pick();
+        assert_eq!(again, Some(port));
+    }
+
+    #[test]
+    fn free_ports_test() {
+        let ports: Vec<_> = free_ports(15000..16000).take(3).collect();
+        assert_eq!(ports.len(), 3);
+        assert!(ports.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ports.iter().all(|port| (15000..16000).contains(port)));
+        assert_eq!(free_ports(15000..15000).next(), None);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_range_test() {
+        use super::pick_unused_port_range_parallel;
+
+        let port = pick_unused_port_range_parallel(15000..65000).expect("No ports free");
+        assert!((15000..65000).contains(&port));
+        assert!(is_free(port));
+        assert_eq!(pick_unused_port_range_parallel(15000..15000), None);
+    }
+
+    #[test]
+    fn multiple_ranges_test() {
+        use super::pick_unused_port_in_ranges;
+
+        let ranges = [15000..16000, 15500..16500, 30000..31000];
+        let port = pick_unused_port_in_ranges(&ranges).expect("No ports free");
+        assert!(ranges.iter().any(|range| range.contains(&port)));
+        assert_eq!(pick_unused_port_in_ranges(&[]), None);
+        assert_eq!(
+            pick_unused_port_in_ranges(&[15000..15000, 16000..16000]),
+            None
+        );
+    }
+
+    #[test]
+    fn highest_free_test() {
+        let port = highest_free_port(15000..16000).expect("No ports free");
+        assert!((15000..16000).contains(&port));
+        assert_eq!(highest_free_port(15000..15000), None);
+        assert_eq!(lowest_free_port(15000..15000), None);
+    }
+
+    #[test]
+    fn preferred_test() {
+        use super::{pick_unused_port_preferred, pick_unused_port_preferring};
+
+        let free = pick_unused_port().expect("No ports free");
+        release_port(free);
+        assert_eq!(pick_unused_port_preferred(free), Some(free));
+
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let busy = listener.local_addr().unwrap().port();
+        let port = pick_unused_port_preferring(&[busy, free]).expect("No ports free");
+        assert_ne!(port, busy);
+        assert_ne!(port, free);
+    }
+
+    #[test]
+    fn random_range_test() {
+        use super::{pick_unused_port_in_random_range, DEFAULT_RANDOM_RANGE};
+
+        assert_eq!(DEFAULT_RANDOM_RANGE, 15000..25000);
+        assert!(pick_unused_port_in_random_range(30000..31000).is_some());
+        // Nothing to draw from, so the OS assigns the port
+        assert!(pick_unused_port_in_random_range(30000..30000).is_some());
+    }
+
+    #[test]
+    fn bind_dual_test() {
+        let (port, listener, socket) = bind_unused_dual().expect("No ports free");
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+        assert_eq!(socket.local_addr().unwrap().port(), port);
+        assert!(!is_free_tcp(port));
+        assert!(!is_free_udp(port));
+    }
+
+    #[test]
+    fn count_free_test() {
+        use super::{contains_free_port, count_free_ports};
+
+        assert!(count_free_ports(15000..15100) > 0);
+        assert!(count_free_ports(15000..15100) <= 100);
+        assert!(contains_free_port(15000..16000));
+        assert_eq!(count_free_ports(15000..15000), 0);
+        assert!(!contains_free_port(15000..15000));
+    }
+
+    #[test]
+    fn family_test() {
+        use super::{Family, PortProbe, Strategy};
+        use std::io;
+        use std::net::SocketAddr;
+
+        let port = PortPicker::new()
+            .family(Family::V4Only)
+            .pick()
+            .expect("No ports free");
+        assert!(is_free_on(Ipv4Addr::UNSPECIFIED.into(), port));
+
+        let v6 = PortPicker::new().family(Family::V6Only).try_pick();
+        assert_eq!(v6.is_ok(), ipv6_available());
+        let v4_first = PortPicker::new().family(Family::V4First).pick();
+        assert!(v4_first.is_some());
+
+        // The OS always assigns 15021, which is taken on IPv6 only
+        struct TakenOnV6;
+
+        impl PortProbe for TakenOnV6 {
+            fn try_bind_tcp(&self, addr: SocketAddr) -> io::Result<u16> {
+                match addr.port() {
+                    0 => Ok(15021),
+                    15021 if addr.is_ipv6() => Err(io::ErrorKind::AddrInUse.into()),
+                    port => Ok(port),
+                }
+            }
+
+            fn try_bind_udp(&self, addr: SocketAddr) -> io::Result<u16> {
+                self.try_bind_tcp(addr)
+            }
+        }
+
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            let picked = PortPicker::new()
+                .family(Family::V4First)
+                .protocol(protocol)
+                .strategy(Strategy::OsOnly)
+                .port_probe(TakenOnV6)
+                .track(false)
+                .pick();
+            assert_eq!(picked, Some(15021).filter(|_| !ipv6_available()));
+        }
+    }
+
+    #[test]
+    fn single_family_test() {
+        use super::{is_free_v4, is_free_v6, pick_unused_port_v4, pick_unused_port_v6};
+
+        let port = pick_unused_port_v4().expect("No ports free");
+        assert!(is_free_v4(port));
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        assert!(!is_free_v4(listener.local_addr().unwrap().port()));
+
+        if TcpListener::bind(("::", 0)).is_ok() {
+            let port = pick_unused_port_v6().expect("No ports free");
+            assert!(is_free_v6(port));
+        }
+    }
+
+    #[test]
+    fn timeout_test() {
+        use super::pick_unused_port_timeout;
+        use std::time::{Duration, Instant};
+
+        assert!(pick_unused_port_timeout(Duration::from_secs(5)).is_some());
+        assert_eq!(pick_unused_port_timeout(Duration::from_secs(0)), None);
+
+        let start = Instant::now();
+        let past = PortPicker::new().deadline(start).pick();
+        assert_eq!(past, None);
+    }
+
+    #[test]
+    fn stepped_test() {
+        use super::pick_unused_port_stepped;
+
+        let port = pick_unused_port_stepped(20001..21000, 2, true).expect("No ports free");
+        assert!((20001..21000).contains(&port));
+        assert_eq!(port % 2, 1);
+        let port = pick_unused_port_stepped(20000..21000, 10, false).expect("No ports free");
+        assert_eq!(port % 10, 0);
+        assert_eq!(pick_unused_port_stepped(20000..21000, 0, false), None);
+        assert_eq!(pick_unused_port_stepped(20000..20000, 1, true), None);
+    }
+
+    #[test]
+    fn predicate_test() {
+        use super::pick_unused_port_where;
+
+        if let Some(port) = pick_unused_port_where(|port| port % 3 == 0) {
+            assert_eq!(port % 3, 0);
+        }
+        assert_eq!(pick_unused_port_where(|_| false), None);
+
+        // Never called for ports failing the built-in checks
+        let skipped = PortPicker::new()
+            .range(24500..24501)
+            .exclude(&[24500])
+            .pick_where(|_| panic!("predicate called"));
+        assert_eq!(skipped, None);
+    }
+
+    #[test]
+    fn socket_addr_test() {
+        use super::{pick_unused_socket_addr, Family};
+
+        let addr = pick_unused_socket_addr().expect("No ports free");
+        assert!(addr.ip().is_unspecified());
+        assert_eq!(addr.is_ipv6(), ipv6_available());
+
+        let v4 = PortPicker::new().family(Family::V4First).pick_socket_addr();
+        assert!(v4.expect("No ports free").is_ipv4());
+        let local = PortPicker::new()
+            .bind_addr(Ipv4Addr::LOCALHOST.into())
+            .pick_socket_addr();
+        assert_eq!(local.expect("No ports free").ip(), Ipv4Addr::LOCALHOST);
+    }
+
+    #[test]
+    fn bind_result_test() {
+        use super::port_bind_result;
+        use std::io::ErrorKind;
+
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let busy = listener.local_addr().unwrap().port();
+        let err = port_bind_result(busy).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+        let free = pick_unused_port().expect("No ports free");
+        assert!(port_bind_result(free).is_ok());
+    }
+
+    #[test]
+    fn loopback_test() {
+        use super::{is_free_tcp_on, pick_unused_port_loopback};
+
+        let port = pick_unused_port_loopback().expect("No ports free");
+        let loopback = Ipv4Addr::LOCALHOST.into();
+        assert!(is_free_on(loopback, port));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).unwrap();
+        assert!(!is_free_tcp_on(loopback, port));
+        drop(listener);
+    }
+
+    #[test]
+    fn strategy_test() {
+        use super::{pick_unused_port_strategy, Strategy};
+
+        assert!(pick_unused_port_strategy(Strategy::OsOnly).is_some());
+        assert!(pick_unused_port_strategy(Strategy::RandomFirst).is_some());
+        // Without any random attempts there is nothing to try
+        let random_only = PortPicker::new()
+            .strategy(Strategy::RandomOnly)
+            .random_attempts(0)
+            .pick();
+        assert_eq!(random_only, None);
+        #[cfg(feature = "rand")]
+        {
+            let port = PortPicker::new()
+                .strategy(Strategy::RandomOnly)
+                .random_attempts(100)
+                .random_range(24000..25000)
+                .pick();
+            assert!((24000..25000).contains(&port.expect("No ports free")));
+        }
+    }
+
+    #[test]
+    fn never_zero_test() {
+        use super::{
+            pick_unused_port_in_ranges, pick_unused_port_preferred, pick_unused_port_stepped,
+            Strategy,
+        };
+
+        assert!(!is_free(0));
+        assert!(!is_free_tcp(0));
+        assert!(!is_free_on(Ipv4Addr::LOCALHOST.into(), 0));
+        assert_eq!(pick_unused_port_range(0..1), None);
+        assert_eq!(lowest_free_port(0..1), None);
+        assert_eq!(highest_free_port(0..1), None);
+        assert_eq!(free_ports(0..1).next(), None);
+        assert_eq!(pick_unused_port_in_ranges(&[0..1, 0..1]), None);
+        assert_eq!(pick_unused_port_stepped(0..1, 1, false), None);
+        assert_ne!(pick_unused_port_preferred(0), Some(0));
+        let random_only = PortPicker::new()
+            .strategy(Strategy::RandomOnly)
+            .random_range(0..1)
+            .pick();
+        assert_eq!(random_only, None);
+        for _ in 0..20 {
+            assert_ne!(pick_unused_port(), Some(0));
+            assert_ne!(bind_unused_tcp().map(|(port, _)| port), Some(0));
+            assert_ne!(bind_unused_udp().map(|(port, _)| port), Some(0));
+        }
+    }
+
+    #[test]
+    fn block_test() {
+        use super::{pick_rtp_port_pair, pick_unused_port_block};
+
+        let block = pick_unused_port_block(4).expect("No ports free");
+        assert_eq!(block.len(), 4);
+        assert!(block.start >= 1024);
+        assert!(block.clone().all(is_free));
+        assert_eq!(pick_unused_port_block(0), None);
+        assert_eq!(pick_unused_port_block(70000), None);
+
+        let (rtp, rtcp) = pick_rtp_port_pair().expect("No ports free");
+        assert_eq!(rtp % 2, 0);
+        assert_eq!(rtcp, rtp + 1);
+        assert!(is_free_udp(rtp) && is_free_udp(rtcp));
+    }
+
+    #[test]
+    fn ipv6_cache_test() {
+        use super::reset_ipv6_cache;
+
+        let available = ipv6_available();
+        reset_ipv6_cache();
+        assert_eq!(ipv6_available(), available);
+        assert_eq!(ipv6_available(), available);
+    }
+
+    #[test]
+    fn verified_test() {
+        use super::pick_unused_port_verified;
+
+        let port = pick_unused_port_verified().expect("No ports free");
+        assert!(is_free(port));
+        let ranged = PortPicker::new().verify(true).range(24000..25000).pick();
+        assert!((24000..25000).contains(&ranged.expect("No ports free")));
+    }
+
+    #[test]
+    fn tcp_only_test() {
+        use super::{is_free_tcp_only, pick_unused_tcp_port};
+
+        let port = pick_unused_tcp_port().expect("No ports free");
+        assert!(is_free_tcp_only(port));
+        let (port, _listener) = bind_unused_tcp().expect("No ports free");
+        assert!(!is_free_tcp_only(port));
+    }
+
+    // Other systems only configure 127.0.0.1 on the loopback interface
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn on_all_test() {
+        use super::{is_free_on_all, pick_unused_port_on_all};
+
+        let ips = [
+            Ipv4Addr::LOCALHOST.into(),
+            Ipv4Addr::new(127, 0, 0, 2).into(),
+        ];
+        let port = pick_unused_port_on_all(&ips).expect("No ports free");
+        assert!(is_free_on_all(&ips, port, Protocol::Both));
+
+        let _listener = TcpListener::bind((Ipv4Addr::new(127, 0, 0, 2), port)).unwrap();
+        assert!(!is_free_on_all(&ips, port, Protocol::Tcp));
+        assert!(is_free_on_all(&ips[..1], port, Protocol::Tcp));
+        assert!(!is_free_on_all(&[], 0, Protocol::Tcp));
+        assert!(pick_unused_port_on_all(&[]).is_some());
+    }
+
+    #[test]
+    fn stats_test() {
+        use super::{pick_unused_port_with_stats, Strategy};
+
+        let (_, stats) = pick_unused_port_with_stats().expect("No ports free");
+        assert!(stats.random_attempts + stats.os_attempts >= 1);
+        assert!(stats.random_attempts <= 10 && stats.os_attempts <= 10);
+
+        let (_, stats) = PortPicker::new()
+            .strategy(Strategy::OsOnly)
+            .pick_with_stats()
+            .expect("No ports free");
+        assert_eq!(stats.random_attempts, 0);
+        assert!(stats.os_attempts >= 1);
+    }
+
+    #[test]
+    fn empty_range_test() {
+        use super::try_pick_unused_port_range;
+
+        let empty = try_pick_unused_port_range(15000..15000);
+        assert!(matches!(empty, Err(PickError::EmptyRange)));
+        let (low, high) = (16000, 15000);
+        let reversed = PortPicker::new().range_inclusive(low..=high).try_pick();
+        assert!(matches!(reversed, Err(PickError::EmptyRange)));
+        let port = try_pick_unused_port_range(15000..16000).expect("No ports free");
+        assert!((15000..16000).contains(&port));
+    }
+
+    #[test]
+    fn v4_first_both_test() {
+        use super::{is_free_udp_on, Family, PortProbe, Strategy};
+        use std::io;
+        use std::net::SocketAddr;
+
+        let addr = PortPicker::new()
+            .family(Family::V4First)
+            .strategy(Strategy::OsOnly)
+            .pick_socket_addr()
+            .expect("No ports free");
+        assert!(addr.is_ipv4());
+        assert!(is_free_udp_on(Ipv4Addr::UNSPECIFIED.into(), addr.port()));
+
+        // The OS always assigns 15020, which is taken on UDP over IPv6 only
+        struct TakenOnV6;
+
+        impl PortProbe for TakenOnV6 {
+            fn try_bind_tcp(&self, addr: SocketAddr) -> io::Result<u16> {
+                Ok(if addr.port() == 0 { 15020 } else { addr.port() })
+            }
+
+            fn try_bind_udp(&self, addr: SocketAddr) -> io::Result<u16> {
+                if addr.is_ipv6() && addr.port() == 15020 {
+                    return Err(io::ErrorKind::AddrInUse.into());
+                }
+                self.try_bind_tcp(addr)
+            }
+        }
+
+        let picker = |family| {
+            PortPicker::new()
+                .family(family)
+                .strategy(Strategy::OsOnly)
+                .port_probe(TakenOnV6)
+                .track(false)
+                .pick()
+        };
+        let expected = Some(15020).filter(|_| !ipv6_available());
+        assert_eq!(picker(Family::V4First), expected);
+        assert_eq!(picker(Family::V4Only), Some(15020));
+    }
+
+    #[test]
+    fn wait_until_free_test() {
+        use super::wait_until_free;
+        use std::time::{Duration, Instant};
+
+        let port = pick_unused_port().expect("No ports free");
+        assert!(wait_until_free(port, Duration::ZERO, Duration::ZERO));
+
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).unwrap();
+        let started = Instant::now();
+        let timeout = Duration::from_millis(50);
+        assert!(!wait_until_free(port, timeout, Duration::from_millis(20)));
+        assert!(started.elapsed() >= timeout);
+        drop(listener);
+        assert!(wait_until_free(
+            port,
+            Duration::from_secs(1),
+            Duration::from_millis(10)
+        ));
+    }
+
+    #[test]
+    fn nonzero_test() {
+        use super::pick_unused_port_nonzero;
+
+        let port = pick_unused_port_nonzero().expect("No ports free");
+        assert!(is_free_tcp(port.get()));
+    }
+
+    #[test]
+    fn rebind_after_probe_test() {
+        use std::net::UdpSocket;
+
+        for _ in 0..20 {
+            let port = pick_unused_port().expect("No ports free");
+            TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).expect("TCP rebind failed");
+            UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).expect("UDP rebind failed");
+            release_port(port);
+        }
+    }
+
+    #[test]
+    fn dry_run_test() {
+        use super::{pick_unused_port_dry_run, DEFAULT_RANDOM_RANGE};
+
+        for _ in 0..100 {
+            assert!(DEFAULT_RANDOM_RANGE.contains(&pick_unused_port_dry_run()));
+        }
+    }
+
+    #[test]
+    fn dualstack_test() {
+        use super::is_free_dualstack_tcp;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(!is_free_dualstack_tcp(port));
+        assert!(!is_free_tcp(port));
+        drop(listener);
+        assert!(!is_free_dualstack_tcp(0));
+        let port = pick_unused_port().expect("No ports free");
+        assert!(is_free_dualstack_tcp(port));
+    }
+
+    #[test]
+    fn ensure_free_test() {
+        use super::ensure_port_free;
+
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        match ensure_port_free(port, Protocol::Both) {
+            Err(PickError::InUse { protocol, addr }) => {
+                assert_eq!(protocol, Protocol::Tcp);
+                assert_eq!(addr.port(), port);
+                assert!(addr.is_ipv4());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        drop(listener);
+        let port = pick_unused_port().expect("No ports free");
+        assert!(ensure_port_free(port, Protocol::Both).is_ok());
+    }
+
+    #[test]
+    fn avoid_test() {
+        use super::pick_unused_port_excluding_range;
+
+        let port = pick_unused_port_excluding_range(15000..25000).expect("No ports free");
+        assert!(!(15000..25000).contains(&port));
+        let port = PortPicker::new()
+            .range(15000..15100)
+            .avoid(15000..15090)
+            .pick()
+            .expect("No ports free");
+        assert!((15090..15100).contains(&port));
+        let covered = PortPicker::new()
+            .range(15000..15100)
+            .avoid(14000..16000)
+            .try_pick();
+        assert!(matches!(covered, Err(PickError::NoPortsAvailable)));
+    }
+
+    #[test]
+    fn range_random_first_test() {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let busy = listener.local_addr().unwrap().port();
+        let picker = PortPicker::new().range(busy..busy + 1).random_attempts(3);
+        assert_eq!(picker.pick_with_stats(), None);
+        let (_, stats) = PortPicker::new()
+            .range(15000..16000)
+            .random_attempts(3)
+            .pick_with_stats()
+            .expect("No ports free");
+        assert!(stats.random_attempts >= 1);
+        assert_eq!(stats.os_attempts, 0);
+    }
+
+    #[test]
+    fn write_port_test() {
+        use super::pick_and_write_port;
+        use std::{env, fs, process};
+
+        let path = env::temp_dir().join(format!("portpicker-{}.port", process::id()));
+        let port = pick_and_write_port(&path).expect("No ports free");
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", port));
+
+        // Threads writing the same path each use their own temporary file
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| release_port(pick_and_write_port(&path).expect("Write failed")));
+            }
+        });
+        fs::remove_file(&path).unwrap();
+
+        let missing = env::temp_dir()
+            .join("portpicker-missing-dir")
+            .join("x.port");
+        assert_eq!(pick_and_write_port(&missing), None);
+    }
+
+    #[test]
+    fn probe_timeout_test() {
+        use std::time::Duration;
+
+        let picker = PortPicker::new().probe_timeout(Duration::from_secs(5));
+        let port = picker.pick().expect("No ports free");
+        assert!(is_free(port));
+        let port = picker.range(15000..16000).pick().expect("No ports free");
+        assert!((15000..16000).contains(&port));
+    }
+
+    #[test]
+    fn resolve_test() {
+        use super::resolve_requested_port;
+
+        assert!(resolve_requested_port("0").is_ok());
+        assert!(resolve_requested_port(" Auto ").is_ok());
+        for spec in ["", "-1", "65536", "http"] {
+            let err = resolve_requested_port(spec);
+            assert!(matches!(err, Err(PickError::InvalidPort(_))), "{:?}", spec);
+        }
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let busy = listener.local_addr().unwrap().port();
+        let err = resolve_requested_port(&busy.to_string());
+        assert!(matches!(err, Err(PickError::InUse { .. })));
+        drop(listener);
+        let port = pick_unused_port().expect("No ports free");
+        assert_eq!(resolve_requested_port(&port.to_string()).ok(), Some(port));
+    }
+
+    #[test]
+    fn for_addr_test() {
+        use super::pick_unused_port_for_addr;
+        use std::net::{SocketAddr, SocketAddrV6};
+
+        let template: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let addr = pick_unused_port_for_addr(template).expect("No ports free");
+        assert_eq!(addr.ip(), template.ip());
+        assert_ne!(addr.port(), 0);
+
+        let listener = TcpListener::bind(template).unwrap();
+        let busy = listener.local_addr().unwrap();
+        let addr = pick_unused_port_for_addr(busy).expect("No ports free");
+        assert_ne!(addr.port(), busy.port());
+
+        if ipv6_available() {
+            let template = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 7, 0));
+            let addr = pick_unused_port_for_addr(template).expect("No ports free");
+            match addr {
+                SocketAddr::V6(v6) => assert_eq!(v6.flowinfo(), 7),
+                SocketAddr::V4(_) => panic!("family changed"),
+            }
+        }
+    }
+
+    #[test]
+    fn source_test() {
+        use super::{pick_unused_port_with_source, Source, Strategy};
+
+        assert!(pick_unused_port_with_source().is_some());
+        let (_, stats) = PortPicker::new()
+            .strategy(Strategy::OsOnly)
+            .pick_with_stats()
+            .expect("No ports free");
+        assert_eq!(stats.source(), Source::OsAssigned);
+        let (_, stats) = PortPicker::new()
+            .range(15000..16000)
+            .pick_with_stats()
+            .expect("No ports free");
+        assert_eq!(stats.source(), Source::RandomInRange);
+    }
+
+    #[test]
+    fn os_backoff_test() {
+        use super::Strategy;
+        use std::time::{Duration, Instant};
+
+        let picker = PortPicker::new()
+            .strategy(Strategy::OsOnly)
+            .os_backoff(Duration::from_millis(20));
+        assert!(picker.pick().is_some());
+        // Every port is rejected, so all attempts are made with two waits
+        let started = Instant::now();
+        let none = picker.clone().os_attempts(3).pick_where(|_| false);
+        assert_eq!(none, None);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn settle_test() {
+        use super::{pick_stable_port, DEFAULT_SETTLE};
+        use std::time::Instant;
+
+        let started = Instant::now();
+        let port = pick_stable_port(DEFAULT_SETTLE).expect("No ports free");
+        assert!(started.elapsed() >= DEFAULT_SETTLE);
+        assert!(is_free(port));
+    }
+
+    #[test]
+    fn port_probe_test() {
+        use super::{PortProbe, Strategy};
+        use std::io;
+        use std::net::SocketAddr;
+
+        // Only ports 15005 to 15009 free, and the OS always assigns 15007
+        struct Scripted;
+
+        impl PortProbe for Scripted {
+            fn try_bind_tcp(&self, addr: SocketAddr) -> io::Result<u16> {
+                match addr.port() {
+                    0 => Ok(15007),
+                    port if (15005..15010).contains(&port) => Ok(port),
+                    _ => Err(io::ErrorKind::AddrInUse.into()),
+                }
+            }
+
+            fn try_bind_udp(&self, addr: SocketAddr) -> io::Result<u16> {
+                self.try_bind_tcp(addr)
+            }
+        }
+
+        let port = PortPicker::new()
+            .port_probe(Scripted)
+            .track(false)
+            .range(15000..15010)
+            .pick()
+            .expect("No ports free");
+        assert!((15005..15010).contains(&port));
+
+        let picker = PortPicker::new()
+            .port_probe(Scripted)
+            .track(false)
+            .strategy(Strategy::OsOnly);
+        assert_eq!(picker.pick(), Some(15007));
+        let busy = PortPicker::new()
+            .port_probe(Scripted)
+            .track(false)
+            .strategy(Strategy::RandomOnly)
+            .random_range(20000..21000)
+            .pick();
+        assert_eq!(busy, None);
+    }
+
+    #[test]
+    fn udp_exclusive_test() {
+        use super::is_free_udp_exclusive;
+        use std::net::UdpSocket;
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        assert!(!is_free_udp_exclusive(port));
+        assert!(!is_free_udp_exclusive(0));
+        drop(socket);
+        let port = pick_unused_port().expect("No ports free");
+        assert!(is_free_udp_exclusive(port));
+    }
+
+    #[test]
+    fn named_ports_test() {
+        use super::pick_named_ports;
+        use std::collections::HashSet;
+
+        let ports = pick_named_ports(&["http", "grpc", "metrics", "http"]).expect("No ports free");
+        assert_eq!(ports.len(), 3);
+        let distinct: HashSet<_> = ports.values().collect();
+        assert_eq!(distinct.len(), 3);
+        assert_eq!(pick_named_ports(&[]).map(|ports| ports.len()), Some(0));
+    }
+
+    #[test]
+    fn bind_tcp_on_test() {
+        use super::{bind_unused_tcp_at, bind_unused_tcp_on, is_free_tcp_on};
+        use std::net::{SocketAddr, SocketAddrV6};
+
+        let (port, listener) = bind_unused_tcp_on(Ipv4Addr::LOCALHOST.into()).unwrap();
+        assert_eq!(
+            listener.local_addr().unwrap(),
+            (Ipv4Addr::LOCALHOST, port).into()
+        );
+        assert!(!is_free_tcp_on(Ipv4Addr::LOCALHOST.into(), port));
+        // Not an address of this host
+        assert!(bind_unused_tcp_on(Ipv4Addr::new(192, 0, 2, 1).into()).is_none());
+
+        if ipv6_available() {
+            let addr = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 1234, 0, 0));
+            let (port, listener) = bind_unused_tcp_at(addr).unwrap();
+            assert_ne!(port, 1234);
+            assert!(listener.local_addr().unwrap().is_ipv6());
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn reuseport_test() {
+        use super::{is_free_tcp_reuseport, is_free_udp_reuseport};
+        use socket2::{Domain, Socket, Type};
+
+        let shared = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        shared.set_reuse_port(true).unwrap();
+        let addr = std::net::SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        shared.bind(&addr.into()).unwrap();
+        shared.listen(128).unwrap();
+        let port = shared.local_addr().unwrap().as_socket().unwrap().port();
+        assert!(is_free_tcp_reuseport(port));
+        assert!(!is_free_tcp(port));
+        let picked = PortPicker::new()
+            .protocol(Protocol::Tcp)
+            .reuse_port(true)
+            .track(false)
+            .range(port..port + 1)
+            .pick();
+        assert_eq!(picked, Some(port));
+
+        let exclusive = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = exclusive.local_addr().unwrap().port();
+        assert!(!is_free_tcp_reuseport(port));
+        assert!(!is_free_udp_reuseport(0));
+    }
+
+    #[test]
+    fn nonblocking_test() {
+        use super::bind_unused_tcp_nonblocking;
+        use std::io::ErrorKind;
+
+        let (port, listener) = bind_unused_tcp_nonblocking().expect("No ports free");
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+        let err = listener.accept().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn sharded_test() {
+        use super::{mix_seed, pick_unused_port_sharded};
+
+        assert_eq!(pick_unused_port_sharded(15000..15000, 1), None);
+        assert_ne!(mix_seed(1) % 64, mix_seed(2) % 64);
+        let port = pick_unused_port_sharded(15000..16000, 7).expect("No ports free");
+        assert!((15000..16000).contains(&port));
+
+        // A busy port is never returned, even from its own shard
+        let listener = std::net::TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let busy = listener.local_addr().unwrap().port();
+        assert_eq!(pick_unused_port_sharded(busy..busy + 1, 3), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn inheritable_test() {
+        use super::reserve_unused_tcp_inheritable;
+        use std::net::TcpListener;
+        use std::os::unix::io::FromRawFd;
+
+        let (port, fd) = reserve_unused_tcp_inheritable().expect("No ports free");
+        let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd)).unwrap();
+        let flags = fdinfo.lines().find_map(|line| line.strip_prefix("flags:"));
+        let flags = u32::from_str_radix(flags.unwrap().trim(), 8).unwrap();
+        // O_CLOEXEC
+        assert_eq!(flags & 0o2000000, 0);
+
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn accept_range_test() {
+        use super::{os_ephemeral_range, Strategy};
+
+        let picker = PortPicker::new().strategy(Strategy::OsOnly).os_attempts(3);
+        assert_eq!(picker.clone().accept_range(1..1024).pick(), None);
+        if let Some(range) = os_ephemeral_range() {
+            let accept = *range.start()..*range.end();
+            let port = picker.accept_range(accept).pick().expect("No ports free");
+            assert!(range.contains(&port));
+        }
+    }
+
+    #[cfg(feature = "if-addrs")]
+    #[test]
+    fn all_interfaces_test() {
+        use super::{interface_ips, is_free_on_all, pick_unused_port_all_interfaces};
+
+        let ips = interface_ips().expect("Can't enumerate interfaces");
+        let port = pick_unused_port_all_interfaces().expect("No ports free");
+        assert!(is_free_on_all(&ips, port, Protocol::Both));
+    }
+
+    #[test]
+    fn availability_test() {
+        use super::{hold_unused_port, probe_unused_port, PortAvailability};
+
+        let probed = probe_unused_port().expect("No ports free");
+        assert!(!probed.is_held());
+        let held = hold_unused_port().expect("No ports free");
+        assert!(held.is_held());
+        let port = held.port();
+        if let PortAvailability::Held(guard) = held {
+            let (listener, _) = guard.into_inner().unwrap();
+            assert_eq!(listener.local_addr().unwrap().port(), port);
+        }
+    }
+
+    #[test]
+    fn as_source_test() {
+        use super::is_free_as_source;
+
+        assert!(!is_free_as_source(0));
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let busy = listener.local_addr().unwrap().port();
+        assert!(!is_free_as_source(busy));
+        let port = pick_unused_port().expect("No ports free");
+        assert!(is_free_as_source(port));
+        assert!(is_free_tcp(port));
+    }
+
+    #[test]
+    fn reserve_block_test() {
+        use super::reserve_unused_block;
+
+        assert!(reserve_unused_block(0).is_none());
+        let guards = reserve_unused_block(3).expect("No ports free");
+        let first = guards[0].port();
+        let ports: Vec<u16> = guards.iter().map(|guard| guard.port()).collect();
+        assert_eq!(ports, vec![first, first + 1, first + 2]);
+    }
+
+    #[test]
+    fn v6_addr_test() {
+        use std::net::Ipv6Addr;
+
+        let mapped = Ipv4Addr::LOCALHOST.to_ipv6_mapped();
+        let err = PortPicker::new().v6_addr(mapped).try_pick().unwrap_err();
+        assert!(matches!(err, PickError::NotIpv6(ip) if ip == mapped));
+        if ipv6_available() {
+            let picker = PortPicker::new().v6_addr(Ipv6Addr::LOCALHOST);
+            let port = picker.pick().expect("No ports free");
+            assert!(is_free_on(Ipv6Addr::LOCALHOST.into(), port));
+        }
+    }
+
+    #[test]
+    fn free_port_from_test() {
+        use super::{first_free_port_down_from, first_free_port_from};
+
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let busy = listener.local_addr().unwrap().port();
+        let up = first_free_port_from(busy).expect("No ports free");
+        assert!(up > busy);
+        let down = first_free_port_down_from(busy).expect("No ports free");
+        assert!(down < busy);
+        assert_eq!(first_free_port_down_from(0), None);
+        if let Some(port) = first_free_port_from(65535) {
+            assert_eq!(port, 65535);
+            release_port(port);
+        }
+    }
+
+    #[test]
+    fn candidate_generator_test() {
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let busy = listener.local_addr().unwrap().port();
+        let free = pick_unused_port().expect("No ports free");
+        release_port(free);
+
+        let candidates = vec![busy, free].into_iter();
+        let picker = PortPicker::new().candidate_generator(candidates);
+        let stats = picker.pick_with_stats().expect("No ports free").1;
+        assert_eq!(stats.random_attempts, 2);
+        assert_eq!(stats.os_attempts, 0);
+
+        // Exhausted, so the OS is asked
+        let (_, stats) = picker.pick_with_stats().expect("No ports free");
+        assert_eq!((stats.random_attempts, stats.os_attempts), (0, 1));
+
+        let endless = PortPicker::new().candidate_generator(std::iter::repeat(busy));
+        let port = endless.random_attempts(3).pick().expect("No ports free");
+        assert_ne!(port, busy);
+    }
+
+    #[test]
+    fn busy_range_test() {
+        use super::{Family, PortProbe};
+        use std::io;
+        use std::net::SocketAddr;
+        use std::sync::{Arc, Mutex};
+
+        // Hold two adjacent ports, so that the whole range is busy
+        let (first, _held) = (0..100)
+            .find_map(|_| {
+                let low = TcpListener::bind(("0.0.0.0", 0)).ok()?;
+                let port = low.local_addr().ok()?.port();
+                let high = TcpListener::bind(("0.0.0.0", port.checked_add(1)?)).ok()?;
+                Some((port, (low, high)))
+            })
+            .expect("No adjacent ports free");
+        let picker = PortPicker::new()
+            .range(first..first + 2)
+            .random_attempts(50);
+        assert_eq!(picker.pick(), None);
+
+        // Reports every port busy, recording which were checked
+        struct Busy(Arc<Mutex<Vec<u16>>>);
+
+        impl PortProbe for Busy {
+            fn try_bind_tcp(&self, addr: SocketAddr) -> io::Result<u16> {
+                self.0.lock().unwrap().push(addr.port());
+                Err(io::ErrorKind::AddrInUse.into())
+            }
+
+            fn try_bind_udp(&self, addr: SocketAddr) -> io::Result<u16> {
+                self.try_bind_tcp(addr)
+            }
+        }
+
+        let checked = Arc::new(Mutex::new(Vec::new()));
+        let picker = PortPicker::new()
+            .range(15000..15004)
+            .random_attempts(50)
+            .family(Family::V4Only)
+            .port_probe(Busy(checked.clone()))
+            .track(false);
+        assert_eq!(picker.pick(), None);
+        let mut checked = checked.lock().unwrap().clone();
+        checked.sort_unstable();
+        assert_eq!(checked, vec![15000, 15001, 15002, 15003]);
+    }
+
+    #[test]
+    fn hold_tcp_udp_test() {
+        use super::try_hold_tcp_udp;
+        use std::net::UdpSocket;
+
+        assert!(try_hold_tcp_udp(0).is_none());
+        let port = pick_unused_port().expect("No ports free");
+        let (listener, socket) = try_hold_tcp_udp(port).expect("Port taken");
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+        assert!(!is_free_udp(port) && !is_free_tcp(port));
+        drop((listener, socket));
+
+        // A UDP socket alone makes the pair fail, leaving TCP free
+        let udp = UdpSocket::bind(("0.0.0.0", 0)).unwrap();
+        let busy = udp.local_addr().unwrap().port();
+        assert!(try_hold_tcp_udp(busy).is_none());
+        assert!(is_free_tcp(busy));
+    }
+
+    #[test]
+    fn both_families_test() {
+        use super::{bind_unused_tcp_both_families, is_free_v4, is_free_v6};
+
+        if !ipv6_available() {
+            return;
+        }
+        let (port, v4, v6) = bind_unused_tcp_both_families().expect("No ports free");
+        assert_eq!(v4.local_addr().unwrap().port(), port);
+        assert_eq!(v6.local_addr().unwrap().port(), port);
+        assert!(!is_free_v4(port) && !is_free_v6(port));
+    }
+
+    #[test]
+    fn by_deadline_test() {
+        use super::pick_unused_port_by_deadline;
+        use std::time::{Duration, Instant};
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(pick_unused_port_by_deadline(deadline).is_some());
+        let passed = Instant::now();
+        assert_eq!(pick_unused_port_by_deadline(passed), None);
+    }
 }
@@ -0,0 +1,132 @@
+use portpicker::{Port, PortPicker, Protocol};
+use std::env;
+use std::ops::RangeInclusive;
+use std::process;
+
+const USAGE: &str = "\
+Usage: portpicker [OPTIONS]
+
+Prints free ports, one per line.
+
+Options:
+    --tcp              Only require the port to be free on TCP
+    --udp              Only require the port to be free on UDP
+    --range LOW-HIGH   Only pick ports between LOW and HIGH, inclusive
+    --count N          Print N distinct ports (default 1)
+    -h, --help         Print this message";
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    protocol: Protocol,
+    range: Option<RangeInclusive<Port>>,
+    count: usize,
+}
+
+// Parse the command line arguments, excluding the program name
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut tcp = false;
+    let mut udp = false;
+    let mut range = None;
+    let mut count = 1;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tcp" => tcp = true,
+            "--udp" => udp = true,
+            "--range" => {
+                let value = args.next().ok_or("--range requires a value")?;
+                range = Some(parse_range(&value)?);
+            }
+            "--count" => {
+                let value = args.next().ok_or("--count requires a value")?;
+                count = value
+                    .parse()
+                    .map_err(|_| format!("invalid count: {}", value))?;
+            }
+            other => return Err(format!("unknown argument: {}", other)),
+        }
+    }
+
+    let protocol = match (tcp, udp) {
+        (true, false) => Protocol::Tcp,
+        (false, true) => Protocol::Udp,
+        _ => Protocol::Both,
+    };
+    Ok(Options {
+        protocol,
+        range,
+        count,
+    })
+}
+
+// Parse an inclusive `LOW-HIGH` range
+fn parse_range(value: &str) -> Result<RangeInclusive<Port>, String> {
+    let invalid = || format!("invalid range: {}", value);
+    let mut parts = value.splitn(2, '-');
+    let low: Port = parts
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(invalid)?;
+    let high: Port = parts
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(invalid)?;
+    if low > high {
+        return Err(invalid());
+    }
+    Ok(low..=high)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let options = parse_args(args).unwrap_or_else(|err| {
+        eprintln!("portpicker: {}\n\n{}", err, USAGE);
+        process::exit(2);
+    });
+
+    let mut picker = PortPicker::new().protocol(options.protocol);
+    if let Some(range) = options.range {
+        picker = picker.range_inclusive(range);
+    }
+
+    match picker.pick_n(options.count) {
+        Some(ports) => {
+            for port in ports {
+                println!("{}", port);
+            }
+        }
+        None => {
+            eprintln!("portpicker: no free ports available");
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, Options};
+    use portpicker::Protocol;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn args_test() {
+        let options = parse(&["--tcp", "--range", "15000-16000", "--count", "3"]).unwrap();
+        assert_eq!(options.protocol, Protocol::Tcp);
+        assert_eq!(options.range, Some(15000..=16000));
+        assert_eq!(options.count, 3);
+
+        assert_eq!(parse(&["--tcp", "--udp"]).unwrap().protocol, Protocol::Both);
+        assert!(parse(&["--range", "16000-15000"]).is_err());
+        assert!(parse(&["--count"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+    }
+}
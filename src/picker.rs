@@ -0,0 +1,631 @@
+use crate::probe::{BindOptions, CustomProbe, PortProbe};
+use crate::{env, registry};
+use crate::{
+    first_ok, inclusive_len, log_attempt, pick_port, wrapping_scan, Family, PickError, PickStats,
+    Port, PortRange, Protocol, Strategy,
+};
+#[cfg(feature = "rand")]
+use rand::Rng;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::ops::{Range, RangeInclusive};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Source of uniformly random indices below the given bound, absent without `rand`
+type Random<'a> = Option<&'a mut dyn FnMut(usize) -> usize>;
+
+// A caller's candidate ports, shared by the clones of a picker and shown
+// without its contents
+#[derive(Clone)]
+struct Candidates(Arc<Mutex<dyn Iterator<Item = Port> + Send>>);
+
+impl fmt::Debug for Candidates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Candidates")
+    }
+}
+
+/// Builder for combining constraints on the picked port
+/// ```rust
+/// use portpicker::{PortPicker, Protocol};
+/// let port: u16 = PortPicker::new()
+///     .protocol(Protocol::Tcp)
+///     .range(15000..16000)
+///     .exclude(&[15000])
+///     .pick()
+///     .expect("No ports free");
+/// ```
+#[derive(Debug, Clone)]
+pub struct PortPicker {
+    protocol: Protocol,
+    // First port and number of ports
+    range: Option<(Port, usize)>,
+    exclude: HashSet<Port>,
+    avoid: Vec<Range<u16>>,
+    // Empty for the unspecified addresses
+    bind_addrs: Vec<IpAddr>,
+    // First port and number of ports
+    random_range: (Port, usize),
+    random_attempts: usize,
+    candidates: Option<Candidates>,
+    os_attempts: usize,
+    strategy: Strategy,
+    verify: bool,
+    settle: Option<Duration>,
+    deadline: Option<Instant>,
+    probe_timeout: Option<Duration>,
+    os_backoff: Option<Duration>,
+    accept_range: Option<Range<u16>>,
+    track: bool,
+    bind_options: BindOptions,
+}
+
+impl Default for PortPicker {
+    fn default() -> Self {
+        PortPicker {
+            protocol: Protocol::Both,
+            range: None,
+            exclude: HashSet::new(),
+            avoid: Vec::new(),
+            bind_addrs: Vec::new(),
+            random_range: env::random_range(),
+            random_attempts: 10,
+            candidates: None,
+            os_attempts: 10,
+            strategy: Strategy::RandomFirst,
+            verify: false,
+            settle: None,
+            deadline: None,
+            probe_timeout: None,
+            os_backoff: None,
+            accept_range: None,
+            track: true,
+            bind_options: BindOptions::default(),
+        }
+    }
+}
+
+impl PortPicker {
+    /// A picker with the same behavior as [`pick_unused_port`](crate::pick_unused_port)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Protocol(s) the port must be free on. Defaults to [`Protocol::Both`].
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Only return ports within `range`. A few random ports within it are
+    /// tried first, see [`random_attempts`](PortPicker::random_attempts), and
+    /// then the rest of the range is scanned starting from a random offset, so
+    /// that no port is checked twice. The OS is never asked to assign a port.
+    /// Picking from
+    /// an empty range fails with [`PickError::EmptyRange`].
+    pub fn range(mut self, range: Range<u16>) -> Self {
+        self.range = Some((range.start, range.len()));
+        self
+    }
+
+    /// Like [`range`](PortPicker::range), but including the upper bound
+    pub fn range_inclusive(mut self, range: RangeInclusive<u16>) -> Self {
+        self.range = Some((*range.start(), inclusive_len(&range)));
+        self
+    }
+
+    /// Like [`range_inclusive`](PortPicker::range_inclusive), with a range
+    /// that is known not to be empty
+    pub fn port_range(mut self, range: PortRange) -> Self {
+        self.range = Some((range.low(), range.count()));
+        self
+    }
+
+    /// Never return any of these ports. Can be called multiple times.
+    pub fn exclude(mut self, ports: &[Port]) -> Self {
+        self.exclude.extend(ports);
+        self
+    }
+
+    /// Never return a port within `range`. Can be called multiple times, and
+    /// combined with [`range`](PortPicker::range) to pick from the rest of it.
+    /// If the avoided ranges cover all of [`range`](PortPicker::range), picking
+    /// fails with [`PickError::NoPortsAvailable`] without binding any port.
+    pub fn avoid(mut self, range: Range<u16>) -> Self {
+        self.avoid.push(range);
+        self
+    }
+
+    /// Only check this address, instead of both the unspecified IPv6 and IPv4
+    /// addresses
+    pub fn bind_addr(mut self, ip: IpAddr) -> Self {
+        self.bind_addrs = vec![ip];
+        self
+    }
+
+    /// Only return ports free on every one of these addresses, such as the
+    /// interfaces of a multi-homed host. An empty slice checks the unspecified
+    /// addresses as usual.
+    pub fn bind_addrs(mut self, ips: &[IpAddr]) -> Self {
+        self.bind_addrs = ips.to_vec();
+        self
+    }
+
+    /// Draw the random candidates from `range`, after any recently
+    /// [released](crate::release_port) ports within it. Defaults to
+    /// [`DEFAULT_RANDOM_RANGE`](crate::DEFAULT_RANDOM_RANGE), or the
+    /// `PORTPICKER_RANGE` environment variable described at
+    /// [`pick_unused_port`](crate::pick_unused_port). Unlike
+    /// [`range`](PortPicker::range), the OS is still asked for a port if none
+    /// of them is free, so the result can lie outside `range`.
+    pub fn random_range(mut self, range: Range<u16>) -> Self {
+        self.random_range = (range.start, range.len());
+        self
+    }
+
+    /// How many random ports to try before asking the OS. Defaults to 10,
+    /// and 0 skips straight to asking the OS. With
+    /// [`range`](PortPicker::range), this many random ports within the range
+    /// are tried before scanning all of it instead.
+    pub fn random_attempts(mut self, attempts: usize) -> Self {
+        self.random_attempts = attempts;
+        self
+    }
+
+    /// Try the ports of `candidates` instead of random ones, such as ports
+    /// weighted towards a favorite sub-range, before asking the OS. At most
+    /// [`random_attempts`](PortPicker::random_attempts) of them are tried per
+    /// pick, which is the only bound on an infinite iterator. Each pick
+    /// continues where the previous one stopped, and the OS is asked once the
+    /// iterator is exhausted. With [`range`](PortPicker::range), candidates
+    /// outside the range are skipped, but still count as attempts.
+    pub fn candidate_generator<I>(mut self, candidates: I) -> Self
+    where
+        I: Iterator<Item = Port> + Send + 'static,
+    {
+        self.candidates = Some(Candidates(Arc::new(Mutex::new(candidates))));
+        self
+    }
+
+    /// How many times to ask the OS for a port before giving up. Defaults to 10.
+    pub fn os_attempts(mut self, attempts: usize) -> Self {
+        self.os_attempts = attempts;
+        self
+    }
+
+    /// Wait between asking the OS for a port again, so that the attempts are
+    /// spread over a short window in which busy ports may be freed. Each wait
+    /// is drawn uniformly between half and all of `backoff` with the same RNG
+    /// as the random candidates, so [`pick_with_rng`](PortPicker::pick_with_rng)
+    /// stays reproducible. Without the `rand` feature it's the full `backoff`.
+    /// Off by default, so the OS is asked again right away. Never waits past
+    /// the [`deadline`](PortPicker::deadline).
+    pub fn os_backoff(mut self, backoff: Duration) -> Self {
+        self.os_backoff = Some(backoff);
+        self
+    }
+
+    /// Only accept ports assigned by the OS within `range`, such as a window
+    /// allowed by a firewall. A port outside it is closed and the OS asked
+    /// again, counting against [`os_attempts`](PortPicker::os_attempts). Off
+    /// by default. The random candidates are unaffected, see
+    /// [`random_range`](PortPicker::random_range). If `range` doesn't overlap
+    /// the [OS ephemeral range](crate::os_ephemeral_range), every OS
+    /// attempt fails.
+    pub fn accept_range(mut self, range: Range<u16>) -> Self {
+        self.accept_range = Some(range);
+        self
+    }
+
+    /// Whether to try random ports, ask the OS, or both. Defaults to
+    /// [`Strategy::RandomFirst`].
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Bind each port found free once more before returning it, to confirm
+    /// it's still free. Off by default. This narrows the window in which
+    /// another process can take the port, but only holding it open with a
+    /// [`PortGuard`](crate::PortGuard) closes it entirely.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Like [`verify`](PortPicker::verify), but wait for `settle` before
+    /// binding again, so that a port only free for a moment between another
+    /// process's rapid binds is rejected. Adds `settle` to every free port
+    /// found, see [`DEFAULT_SETTLE`](crate::DEFAULT_SETTLE) for a small value.
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.verify = true;
+        self.settle = Some(settle);
+        self
+    }
+
+    /// Stop trying ports once `deadline` has passed. This is checked between
+    /// attempts, so a bind in progress is never interrupted.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Give up on a single port if checking it takes longer than `timeout`,
+    /// treating it as busy, so that a bind hanging in some network namespace
+    /// setups doesn't wedge the picker. Off by default.
+    ///
+    /// As a bind can't be cancelled, every check then runs on a new thread that
+    /// is waited on for at most `timeout`, and a stuck thread is left behind.
+    /// This costs a thread spawn per port, so only enable it when needed.
+    pub fn probe_timeout(mut self, timeout: Duration) -> Self {
+        self.probe_timeout = Some(timeout);
+        self
+    }
+
+    /// Set `SO_REUSEADDR` on the probe sockets, so that ports with connections
+    /// lingering in `TIME_WAIT` count as free. Off by default, see
+    /// [`is_free_tcp_reuse`](crate::is_free_tcp_reuse).
+    pub fn reuse_addr(mut self, reuse: bool) -> Self {
+        self.bind_options.reuse_addr = reuse;
+        self
+    }
+
+    /// Set `SO_REUSEPORT` on the probe sockets, so that ports held only by
+    /// sockets that set it too count as free, as they would for a server
+    /// running several workers on one port. Off by default, see
+    /// [`is_free_tcp_reuseport`](crate::is_free_tcp_reuseport).
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.bind_options.reuse_port = reuse;
+        self
+    }
+
+    /// Check ports with `probe` instead of binding real sockets, such as a
+    /// fake that reports a scripted set of ports as taken. IPv6 availability
+    /// is still detected on the host, and `reuse_addr` is up to the probe.
+    /// Combine it with [`track(false)`](PortPicker::track) so that the fake's
+    /// ports neither block nor depend on the real picks of this process.
+    pub fn port_probe<P: PortProbe + 'static>(mut self, probe: P) -> Self {
+        self.bind_options.custom = Some(CustomProbe(Arc::new(probe)));
+        self
+    }
+
+    /// Remember each picked port in this process until it's passed to
+    /// [`release_port`](crate::release_port), so that no other picker returns
+    /// it in the meantime. On by default. Without tracking, ports handed out
+    /// by other pickers can be returned, and recently released ports aren't
+    /// preferred.
+    pub fn track(mut self, track: bool) -> Self {
+        self.track = track;
+        self
+    }
+
+    /// Address families to bind, and in which order. Defaults to
+    /// [`Family::V6First`], and `V4Only` or `V6Only` ignore the other family.
+    pub fn family(mut self, family: Family) -> Self {
+        self.bind_options.family = family;
+        self
+    }
+
+    /// Bind `ip` instead of the unspecified IPv6 address, such as a loopback
+    /// address that works where `::1` is unreachable. Defaults to `::`, and
+    /// ignored for the [`bind_addrs`](PortPicker::bind_addrs) if set. An
+    /// IPv4-mapped address would bind IPv4 instead, so picking then fails
+    /// with [`PickError::NotIpv6`].
+    pub fn v6_addr(mut self, ip: Ipv6Addr) -> Self {
+        self.bind_options.v6_addr = ip;
+        self
+    }
+
+    /// Picks a port matching all the constraints.
+    ///
+    /// The port is not returned again by any picker in this process until it's
+    /// passed to [`release_port`](crate::release_port), unless tracking is
+    /// turned off with [`track`](PortPicker::track).
+    pub fn pick(&self) -> Option<Port> {
+        self.try_pick().ok()
+    }
+
+    /// Picks `n` mutually distinct ports matching all the constraints. Each
+    /// chosen port is excluded from the following picks. Returns `None`
+    /// rather than a partial result if fewer than `n` ports could be found.
+    pub fn pick_n(&self, n: usize) -> Option<Vec<Port>> {
+        let mut picker = self.clone();
+        let mut ports = Vec::with_capacity(n);
+        for _ in 0..n {
+            match picker.pick() {
+                Some(port) => {
+                    picker.exclude.insert(port);
+                    ports.push(port);
+                }
+                None => {
+                    ports.into_iter().for_each(|port| picker.release(port));
+                    return None;
+                }
+            }
+        }
+        Some(ports)
+    }
+
+    /// Picks a port matching all the constraints, reporting why if none
+    /// could be found
+    pub fn try_pick(&self) -> Result<Port, PickError> {
+        self.pick_addr_where(|_| true, &mut PickStats::default())
+            .map(|addr| addr.port())
+    }
+
+    /// Picks a port matching all the constraints, returning the address it
+    /// was found free on: the first [`bind_addr`](PortPicker::bind_addr) if set,
+    /// otherwise the unspecified address of the first family bound
+    pub fn pick_socket_addr(&self) -> Option<SocketAddr> {
+        self.pick_addr_where(|_| true, &mut PickStats::default())
+            .ok()
+    }
+
+    /// Picks a port matching all the constraints that `extra` also accepts.
+    /// `extra` is only called for ports that passed the built-in checks.
+    pub fn pick_where<F: Fn(Port) -> bool>(&self, extra: F) -> Option<Port> {
+        self.pick_addr_where(extra, &mut PickStats::default())
+            .ok()
+            .map(|addr| addr.port())
+    }
+
+    /// Picks a port matching all the constraints, also reporting how many
+    /// attempts it took
+    pub fn pick_with_stats(&self) -> Option<(Port, PickStats)> {
+        let mut stats = PickStats::default();
+        let addr = self.pick_addr_where(|_| true, &mut stats).ok()?;
+        Some((addr.port(), stats))
+    }
+
+    fn pick_addr_where<F: Fn(Port) -> bool>(
+        &self,
+        extra: F,
+        stats: &mut PickStats,
+    ) -> Result<SocketAddr, PickError> {
+        #[cfg(feature = "rand")]
+        {
+            let mut rng = rand::thread_rng();
+            self.run(Some(&mut |n| rng.gen_range(0..n)), &extra, stats)
+        }
+        #[cfg(not(feature = "rand"))]
+        {
+            self.run(None, &extra, stats)
+        }
+    }
+
+    /// Picks a port matching all the constraints, drawing random candidates
+    /// from the supplied RNG
+    #[cfg(feature = "rand")]
+    pub fn pick_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> Result<Port, PickError> {
+        let stats = &mut PickStats::default();
+        self.run(Some(&mut |n| rng.gen_range(0..n)), &|_| true, stats)
+            .map(|addr| addr.port())
+    }
+
+    fn run(
+        &self,
+        random: Random<'_>,
+        extra: &dyn Fn(Port) -> bool,
+        stats: &mut PickStats,
+    ) -> Result<SocketAddr, PickError> {
+        let v6_addr = self.bind_options.v6_addr;
+        if v6_addr.to_ipv4_mapped().is_some() {
+            return Err(PickError::NotIpv6(v6_addr));
+        }
+        // Caller's predicate, rejecting like a busy port
+        let accept = |port: Port| {
+            if extra(port) {
+                Ok(())
+            } else {
+                Err(PickError::NoPortsAvailable)
+            }
+        };
+        let excluded = |port: Port| {
+            self.exclude.contains(&port) || self.avoid.iter().any(|range| range.contains(&port))
+        };
+        let expired = || {
+            self.deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        };
+        let probe = |port: Port| {
+            let (options, protocol) = (self.bind_options.clone(), self.protocol);
+            let addrs = self.bind_addrs.clone();
+            self.timed(move || options.probe_on_all(protocol, &addrs, port))
+        };
+        // Bind once more, after the sockets of the first check were closed
+        let verify = |addr: SocketAddr| {
+            if self.verify {
+                if let Some(settle) = self.settle {
+                    thread::sleep(settle);
+                }
+                probe(addr.port()).map(|_| addr)
+            } else {
+                Ok(addr)
+            }
+        };
+        let check = |port: Port| {
+            if expired() || excluded(port) || !self.claim(port) {
+                return Err(PickError::NoPortsAvailable);
+            }
+            let result = probe(port)
+                .and_then(verify)
+                .and_then(|addr| accept(port).map(|_| addr));
+            if result.is_err() {
+                self.release(port);
+            }
+            result
+        };
+
+        if let Some((start, len)) = self.range {
+            if len == 0 {
+                return Err(PickError::EmptyRange);
+            }
+            // Random ports within the range first, to spread out concurrent
+            // callers, then every port from a random offset as a last resort
+            let in_range = |port: &Port| *port >= start && ((*port - start) as usize) < len;
+            let generated = self.generated(in_range);
+            let (tries, offset) = match random {
+                Some(random) => {
+                    let tries: Vec<Port> = generated.unwrap_or_else(|| {
+                        (0..self.random_attempts)
+                            .map(|_| start + random(len) as Port)
+                            .collect()
+                    });
+                    (tries, random(len))
+                }
+                None => (generated.unwrap_or_default(), 0),
+            };
+            let mut last_err = None;
+            // Every port is checked at most once, so a fully busy range gives
+            // up after `len` attempts
+            let mut seen = HashSet::new();
+            let ports = tries
+                .into_iter()
+                .chain(wrapping_scan(start, len, offset))
+                .filter(|port| seen.insert(*port));
+            let attempts = ports.map(|port| {
+                stats.random_attempts += 1;
+                let attempt = check(port);
+                log_attempt("port in range", port, &attempt);
+                attempt
+            });
+            return match first_ok(attempts, &mut last_err) {
+                Some(addr) => {
+                    debug!("picked port {} in range", addr);
+                    Ok(addr)
+                }
+                None => {
+                    let err = last_err.unwrap_or(PickError::NoPortsAvailable);
+                    debug!("giving up on range: {}", err);
+                    Err(err)
+                }
+            };
+        }
+
+        let (start, len) = self.random_range;
+        let os_only = self.strategy == Strategy::OsOnly;
+        let generated = if os_only {
+            None
+        } else {
+            self.generated(|_| true)
+        };
+        let random_phase = !os_only && (generated.is_some() || random.is_some() && len > 0);
+        let random_attempts = if random_phase {
+            self.random_attempts
+        } else {
+            0
+        };
+        // Shared by the random phase and the backoff of the OS phase
+        let random = RefCell::new(random);
+        let draw = |n: usize| random.borrow_mut().as_mut().map(|random| random(n));
+        // Recently released ports within the random range are tried first,
+        // unless the caller supplied the candidates
+        let in_range = move |port: &Port| *port >= start && ((*port - start) as usize) < len;
+        let candidates: Box<dyn Iterator<Item = Port>> = match generated {
+            Some(generated) => Box::new(generated.into_iter()),
+            None => {
+                let recent = if self.track {
+                    registry::recently_released()
+                } else {
+                    Vec::new()
+                };
+                let recent = recent.into_iter().filter(in_range);
+                let drawn = std::iter::from_fn(|| draw(len).map(|i| start + i as Port));
+                Box::new(recent.chain(drawn))
+            }
+        };
+        let candidates = candidates.take(random_attempts);
+        let asked = Cell::new(0);
+        let ask = || {
+            if asked.replace(asked.get() + 1) > 0 {
+                self.back_off(&draw);
+            }
+            if expired() {
+                return Err(PickError::NoPortsAvailable);
+            }
+            let (options, protocol) = (self.bind_options.clone(), self.protocol);
+            let addrs = self.bind_addrs.clone();
+            let addr = self.timed(move || options.ask_on_all(protocol, &addrs))?;
+            let port = addr.port();
+            let accepted = self
+                .accept_range
+                .as_ref()
+                .map_or(true, |range| range.contains(&port));
+            if port == 0 || !accepted || excluded(port) || !self.claim(port) {
+                return Err(PickError::NoPortsAvailable);
+            }
+            let result = verify(addr).and_then(|addr| accept(port).map(|_| addr));
+            if result.is_err() {
+                self.release(port);
+            }
+            result
+        };
+        let os_attempts = match self.strategy {
+            Strategy::RandomOnly => 0,
+            _ => self.os_attempts,
+        };
+        pick_port(candidates, check, ask, os_attempts, stats)
+    }
+
+    // Hand out `port` in the registry if tracking, false if it already was
+    fn claim(&self, port: Port) -> bool {
+        !self.track || registry::claim(port)
+    }
+
+    // Undo `claim`
+    fn release(&self, port: Port) {
+        if self.track {
+            registry::release_port(port);
+        }
+    }
+
+    // Those of the next `random_attempts` caller candidates that `keep` accepts,
+    // if a generator was set
+    fn generated(&self, keep: impl Fn(&Port) -> bool) -> Option<Vec<Port>> {
+        let Candidates(candidates) = self.candidates.as_ref()?;
+        let mut candidates = candidates.lock().unwrap_or_else(|err| err.into_inner());
+        let kept = (&mut *candidates).take(self.random_attempts).filter(keep);
+        Some(kept.collect())
+    }
+
+    // Sleep between OS attempts for between half and all of the backoff, drawn
+    // with `draw` if there's an RNG, without sleeping past the deadline
+    fn back_off(&self, draw: &dyn Fn(usize) -> Option<usize>) {
+        let backoff = match self.os_backoff {
+            Some(backoff) => backoff,
+            None => return,
+        };
+        let half = backoff / 2;
+        let micros = half.as_micros().min(usize::MAX as u128) as usize;
+        let jitter = draw(micros + 1).map_or(half, |j| Duration::from_micros(j as u64));
+        let mut sleep = half + jitter;
+        if let Some(deadline) = self.deadline {
+            sleep = sleep.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        thread::sleep(sleep);
+    }
+
+    // Run `bind` on a helper thread if there's a probe timeout, treating a
+    // timed out bind like a busy port
+    fn timed<F>(&self, bind: F) -> Result<SocketAddr, PickError>
+    where
+        F: FnOnce() -> Result<SocketAddr, PickError> + Send + 'static,
+    {
+        let timeout = match self.probe_timeout {
+            Some(timeout) => timeout,
+            None => return bind(),
+        };
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(bind());
+        });
+        receiver
+            .recv_timeout(timeout)
+            .unwrap_or(Err(PickError::NoPortsAvailable))
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{reserve_unused_port, Port, PortGuard};
+use std::sync::{Mutex, MutexGuard};
+
+/// A batch of ports reserved up front and leased out one at a time, for
+/// harnesses that need ports over and over without probing each time.
+///
+/// Every port is held by a [`PortGuard`] for as long as the pool lives, also
+/// while leased out, so binding it requires the reuse options described there.
+/// The pool is `Send + Sync` and can be shared from a static `OnceLock`.
+/// ```rust
+/// use portpicker::PortPool;
+/// let pool = PortPool::new(4).expect("No ports free");
+/// let lease = pool.lease().expect("All ports leased");
+/// println!("leased port {}", lease.port());
+/// ```
+#[derive(Debug)]
+pub struct PortPool {
+    free: Mutex<Vec<PortGuard>>,
+}
+
+impl PortPool {
+    /// Reserve `n` distinct ports, or `None` if that many couldn't be reserved
+    pub fn new(n: usize) -> Option<Self> {
+        let guards = (0..n)
+            .map(|_| reserve_unused_port())
+            .collect::<Option<Vec<_>>>()?;
+        Some(PortPool {
+            free: Mutex::new(guards),
+        })
+    }
+
+    /// Check out a port until the lease is dropped, or `None` if all ports
+    /// are currently leased
+    pub fn lease(&self) -> Option<PortLease<'_>> {
+        let guard = self.free().pop()?;
+        Some(PortLease {
+            pool: self,
+            port: guard.port(),
+            guard: Some(guard),
+        })
+    }
+
+    /// Number of ports not currently leased
+    pub fn available(&self) -> usize {
+        self.free().len()
+    }
+
+    fn free(&self) -> MutexGuard<'_, Vec<PortGuard>> {
+        // The list stays consistent even if a holder panicked
+        self.free.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// A port checked out of a [`PortPool`], returned to the pool when dropped
+#[derive(Debug)]
+pub struct PortLease<'a> {
+    pool: &'a PortPool,
+    port: Port,
+    // Only taken by `into_guard` and on drop
+    guard: Option<PortGuard>,
+}
+
+impl PortLease<'_> {
+    /// The leased port
+    pub fn port(&self) -> Port {
+        self.port
+    }
+
+    /// Take the port out of the pool for good, returning the guard holding it
+    pub fn into_guard(mut self) -> PortGuard {
+        self.guard.take().expect("lease already returned")
+    }
+}
+
+impl Drop for PortLease<'_> {
+    fn drop(&mut self) {
+        if let Some(guard) = self.guard.take() {
+            self.pool.free().push(guard);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PortPool;
+
+    #[test]
+    fn pool_test() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PortPool>();
+
+        let pool = PortPool::new(2).expect("No ports free");
+        let first = pool.lease().unwrap();
+        let second = pool.lease().unwrap();
+        assert_ne!(first.port(), second.port());
+        assert!(pool.lease().is_none());
+
+        let port = first.port();
+        drop(first);
+        assert_eq!(pool.available(), 1);
+        assert_eq!(pool.lease().unwrap().port(), port);
+
+        second.into_guard();
+        assert_eq!(pool.available(), 1);
+    }
+}
@@ -0,0 +1,519 @@
+use crate::{Family, PickError, Port, Protocol};
+use socket2::{Domain, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+pub(crate) const UNSPECIFIED_V4: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+pub(crate) const UNSPECIFIED_V6: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+
+// IPv6 availability: unknown, available or unavailable
+static IPV6: AtomicU8 = AtomicU8::new(IPV6_UNKNOWN);
+const IPV6_UNKNOWN: u8 = 0;
+const IPV6_AVAILABLE: u8 = 1;
+const IPV6_UNAVAILABLE: u8 = 2;
+
+/// Check if IPv6 sockets can be bound on this host at all.
+///
+/// This is detected once with a probe bind and then cached, see
+/// [`reset_ipv6_cache`]. When IPv6 is unavailable, the dual-stack checks such
+/// as [`is_free`](crate::is_free) skip the IPv6 binds entirely and only
+/// require the IPv4 binds to succeed.
+pub fn ipv6_available() -> bool {
+    match IPV6.load(Ordering::Relaxed) {
+        IPV6_AVAILABLE => true,
+        IPV6_UNAVAILABLE => false,
+        _ => {
+            let available = TcpListener::bind(SocketAddr::new(UNSPECIFIED_V6, 0)).is_ok();
+            let state = if available {
+                IPV6_AVAILABLE
+            } else {
+                IPV6_UNAVAILABLE
+            };
+            IPV6.store(state, Ordering::Relaxed);
+            available
+        }
+    }
+}
+
+/// Forget the cached result of [`ipv6_available`], so that the next check
+/// probes again. Meant for tests that enable or disable IPv6 at runtime.
+pub fn reset_ipv6_cache() {
+    IPV6.store(IPV6_UNKNOWN, Ordering::Relaxed);
+}
+
+/// The binds done to check whether a port is free, replaceable with a fake
+/// through [`PortPicker::port_probe`](crate::PortPicker::port_probe) to test
+/// code that picks ports without touching the network.
+///
+/// Both methods return the bound port, which is the one the OS assigned if
+/// `addr` has port 0, and an `AddrInUse` error if the port is taken.
+/// ```rust
+/// use portpicker::{PortPicker, PortProbe};
+/// use std::io;
+/// use std::net::SocketAddr;
+///
+/// // Reports every port below 20000 as taken
+/// struct Crowded;
+///
+/// impl PortProbe for Crowded {
+///     fn try_bind_tcp(&self, addr: SocketAddr) -> io::Result<u16> {
+///         match addr.port() {
+///             0 => Ok(40000),
+///             port if port < 20000 => Err(io::ErrorKind::AddrInUse.into()),
+///             port => Ok(port),
+///         }
+///     }
+///
+///     fn try_bind_udp(&self, addr: SocketAddr) -> io::Result<u16> {
+///         self.try_bind_tcp(addr)
+///     }
+/// }
+///
+/// let port = PortPicker::new().port_probe(Crowded).pick().expect("No ports free");
+/// assert!(port >= 20000);
+/// ```
+pub trait PortProbe: Send + Sync {
+    /// Bind a TCP listener on `addr`, returning the bound port
+    fn try_bind_tcp(&self, addr: SocketAddr) -> io::Result<Port>;
+
+    /// Bind a UDP socket on `addr`, returning the bound port
+    fn try_bind_udp(&self, addr: SocketAddr) -> io::Result<Port>;
+}
+
+/// The real binds done by the free functions, closing each socket right away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct OsProbe;
+
+impl PortProbe for OsProbe {
+    fn try_bind_tcp(&self, addr: SocketAddr) -> io::Result<Port> {
+        Ok(DEFAULT_BIND.tcp(addr)?.port())
+    }
+
+    fn try_bind_udp(&self, addr: SocketAddr) -> io::Result<Port> {
+        Ok(DEFAULT_BIND.udp(addr)?.port())
+    }
+}
+
+// A probe set on a picker, shown without its contents
+#[derive(Clone)]
+pub(crate) struct CustomProbe(pub(crate) Arc<dyn PortProbe>);
+
+impl fmt::Debug for CustomProbe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomProbe")
+    }
+}
+
+/// Socket options applied to the probe binds
+#[derive(Debug, Clone)]
+pub(crate) struct BindOptions {
+    pub(crate) reuse_addr: bool,
+    // Only ever set on Linux and FreeBSD
+    pub(crate) reuse_port: bool,
+    pub(crate) family: Family,
+    // Bound in place of the unspecified IPv6 address
+    pub(crate) v6_addr: Ipv6Addr,
+    // Replaces the real binds if set
+    pub(crate) custom: Option<CustomProbe>,
+}
+
+/// Plain binds, as done by the free functions
+pub(crate) const DEFAULT_BIND: BindOptions = BindOptions {
+    reuse_addr: false,
+    reuse_port: false,
+    family: Family::V6First,
+    v6_addr: Ipv6Addr::UNSPECIFIED,
+    custom: None,
+};
+
+impl Default for BindOptions {
+    fn default() -> Self {
+        DEFAULT_BIND
+    }
+}
+
+impl BindOptions {
+    // Whether std's plain bind matches these options. On Windows every probe
+    // needs SO_EXCLUSIVEADDRUSE, which std doesn't set.
+    fn use_std(&self) -> bool {
+        !self.reuse_addr && !self.reuse_port && !cfg!(windows) && self.custom.is_none()
+    }
+
+    // The unspecified addresses to bind, with `v6_addr` for IPv6, in order of
+    // preference. IPv6 is skipped if unavailable, unless it's the only family
+    // allowed.
+    fn unspecified(&self) -> impl Iterator<Item = IpAddr> {
+        let v6_addr = IpAddr::V6(self.v6_addr);
+        let v6 = Some(v6_addr).filter(|_| ipv6_available());
+        let v4 = Some(UNSPECIFIED_V4);
+        let addrs = match self.family {
+            Family::V6First => [v6, v4],
+            Family::V4First => [v4, v6],
+            Family::V4Only => [v4, None],
+            Family::V6Only => [Some(v6_addr), None],
+        };
+        IntoIterator::into_iter(addrs).flatten()
+    }
+
+    // Try to bind to a socket using UDP
+    pub(crate) fn udp(&self, addr: SocketAddr) -> Result<SocketAddr, PickError> {
+        let bound = if let Some(CustomProbe(probe)) = &self.custom {
+            probe
+                .try_bind_udp(addr)
+                .map(|port| SocketAddr::new(addr.ip(), port))
+        } else if self.use_std() {
+            UdpSocket::bind(addr).and_then(|socket| close(socket, UdpSocket::local_addr))
+        } else {
+            self.bind_socket(addr, Type::DGRAM, false, false)
+        };
+        bound.map_err(|err| PickError::from_bind(addr, err))
+    }
+
+    // Try to bind to a socket using TCP
+    pub(crate) fn tcp(&self, addr: SocketAddr) -> Result<SocketAddr, PickError> {
+        let bound = if let Some(CustomProbe(probe)) = &self.custom {
+            probe
+                .try_bind_tcp(addr)
+                .map(|port| SocketAddr::new(addr.ip(), port))
+        } else if self.use_std() {
+            TcpListener::bind(addr).and_then(|listener| close(listener, TcpListener::local_addr))
+        } else {
+            self.bind_socket(addr, Type::STREAM, true, false)
+        };
+        bound.map_err(|err| PickError::from_bind(addr, err))
+    }
+
+    // Like `udp`, but an IPv6 bind sets `IPV6_V6ONLY`, so that `[::]` doesn't
+    // also take the IPv4 port and each family is checked on its own
+    pub(crate) fn udp_one_family(&self, addr: SocketAddr) -> Result<SocketAddr, PickError> {
+        if addr.is_ipv4() || self.custom.is_some() {
+            return self.udp(addr);
+        }
+        self.bind_socket(addr, Type::DGRAM, false, true)
+            .map_err(|err| PickError::from_bind(addr, err))
+    }
+
+    // Like `tcp`, but an IPv6 bind sets `IPV6_V6ONLY`
+    pub(crate) fn tcp_one_family(&self, addr: SocketAddr) -> Result<SocketAddr, PickError> {
+        if addr.is_ipv4() || self.custom.is_some() {
+            return self.tcp(addr);
+        }
+        self.bind_socket(addr, Type::STREAM, true, true)
+            .map_err(|err| PickError::from_bind(addr, err))
+    }
+
+    // Bind a socket with these options set, returning the bound address
+    fn bind_socket(
+        &self,
+        addr: SocketAddr,
+        ty: Type,
+        listen: bool,
+        only_v6: bool,
+    ) -> io::Result<SocketAddr> {
+        let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+        if only_v6 {
+            socket.set_only_v6(true)?;
+        }
+        // Like std's TCP bind, so that `TIME_WAIT` is treated the same
+        #[cfg(unix)]
+        if listen && self.use_std() {
+            socket.set_reuse_address(true)?;
+        }
+        if self.reuse_addr {
+            socket.set_reuse_address(true)?;
+        } else {
+            #[cfg(windows)]
+            set_exclusive_addr_use(&socket)?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+        if self.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        socket.bind(&addr.into())?;
+        if listen {
+            socket.listen(128)?;
+        }
+        close_socket(socket)
+    }
+
+    // Check UDP on every allowed family, returning the first address bound
+    pub(crate) fn probe_udp(&self, port: Port) -> Result<SocketAddr, PickError> {
+        reject_zero(port)?;
+        self.every_family(|ip| self.udp(SocketAddr::new(ip, port)))
+    }
+
+    // Check TCP on every allowed family, returning the first address bound
+    pub(crate) fn probe_tcp(&self, port: Port) -> Result<SocketAddr, PickError> {
+        reject_zero(port)?;
+        // On Linux a dual-stack bind conflicts with exactly the same sockets as
+        // the two separate binds, so one bind is enough
+        #[cfg(target_os = "linux")]
+        if self.family == Family::V6First && self.v6_addr.is_unspecified() && self.use_std() {
+            if let Some(result) = dualstack_tcp(port) {
+                return result;
+            }
+        }
+        self.every_family(|ip| self.tcp(SocketAddr::new(ip, port)))
+    }
+
+    // Check UDP on every allowed family with sockets that can't be shared
+    pub(crate) fn probe_udp_exclusive(&self, port: Port) -> Result<SocketAddr, PickError> {
+        reject_zero(port)?;
+        self.every_family(|ip| {
+            let addr = SocketAddr::new(ip, port);
+            udp_exclusive(addr).map_err(|err| PickError::from_bind(addr, err))
+        })
+    }
+
+    // Bind on every allowed family, stopping at the first failure
+    fn every_family<F>(&self, bind: F) -> Result<SocketAddr, PickError>
+    where
+        F: Fn(IpAddr) -> Result<SocketAddr, PickError>,
+    {
+        let mut first = None;
+        for ip in self.unspecified() {
+            let addr = bind(ip)?;
+            first.get_or_insert(addr);
+        }
+        first.ok_or(PickError::NoPortsAvailable)
+    }
+
+    // Check the given protocol(s) on every allowed family
+    pub(crate) fn probe(&self, proto: Protocol, port: Port) -> Result<SocketAddr, PickError> {
+        match proto {
+            Protocol::Tcp => self.probe_tcp(port),
+            Protocol::Udp => self.probe_udp(port),
+            Protocol::Both => self
+                .probe_tcp(port)
+                .and_then(|addr| self.probe_udp(port).map(|_| addr)),
+        }
+    }
+
+    // Bind one socket at a time, returning the first protocol and address on
+    // which the port is taken
+    pub(crate) fn find_in_use(
+        &self,
+        proto: Protocol,
+        port: Port,
+    ) -> Option<(Protocol, SocketAddr)> {
+        let protocols: &[Protocol] = match proto {
+            Protocol::Tcp => &[Protocol::Tcp],
+            Protocol::Udp => &[Protocol::Udp],
+            Protocol::Both => &[Protocol::Tcp, Protocol::Udp],
+        };
+        for &protocol in protocols {
+            for ip in self.unspecified() {
+                let addr = SocketAddr::new(ip, port);
+                // IPv6-only, so that a port taken on IPv4 alone is reported
+                // on the IPv4 address
+                let bound = match protocol {
+                    Protocol::Tcp => self.tcp_one_family(addr),
+                    _ => self.udp_one_family(addr),
+                };
+                if bound.is_err_and(|err| err.is_busy()) {
+                    return Some((protocol, addr));
+                }
+            }
+        }
+        None
+    }
+
+    // Check the given protocol(s) on a single address
+    pub(crate) fn probe_on(
+        &self,
+        proto: Protocol,
+        ip: IpAddr,
+        port: Port,
+    ) -> Result<SocketAddr, PickError> {
+        reject_zero(port)?;
+        let addr = SocketAddr::new(ip, port);
+        match proto {
+            Protocol::Tcp => self.tcp(addr),
+            Protocol::Udp => self.udp(addr),
+            Protocol::Both => self
+                .tcp(addr)
+                .and_then(|bound| self.udp(addr).map(|_| bound)),
+        }
+    }
+
+    // Check the given protocol(s) on each of `ips`, returning the first address
+    // bound. No addresses means every allowed family of the unspecified address.
+    pub(crate) fn probe_on_all(
+        &self,
+        proto: Protocol,
+        ips: &[IpAddr],
+        port: Port,
+    ) -> Result<SocketAddr, PickError> {
+        let (first, rest) = match ips.split_first() {
+            Some(split) => split,
+            None => return self.probe(proto, port),
+        };
+        let addr = self.probe_on(proto, *first, port)?;
+        for ip in rest {
+            self.probe_on(proto, *ip, port)?;
+        }
+        Ok(addr)
+    }
+
+    // Asks the OS for a free TCP port on the first family that works, then
+    // checks that port on every allowed family
+    pub(crate) fn ask_tcp(&self) -> Result<SocketAddr, PickError> {
+        let addr = self.first_family(|ip| self.tcp(SocketAddr::new(ip, 0)))?;
+        self.probe_tcp(addr.port()).map(|_| addr)
+    }
+
+    // Asks the OS for a free UDP port, like `ask_tcp`
+    pub(crate) fn ask_udp(&self) -> Result<SocketAddr, PickError> {
+        let addr = self.first_family(|ip| self.udp(SocketAddr::new(ip, 0)))?;
+        self.probe_udp(addr.port()).map(|_| addr)
+    }
+
+    // The first successful bind over the allowed families, or the last error
+    fn first_family<F>(&self, bind: F) -> Result<SocketAddr, PickError>
+    where
+        F: Fn(IpAddr) -> Result<SocketAddr, PickError>,
+    {
+        let mut last_err = PickError::NoPortsAvailable;
+        for ip in self.unspecified() {
+            match bind(ip) {
+                Ok(addr) => return Ok(addr),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    // Ask the OS for a port free on the given protocol(s)
+    pub(crate) fn ask(&self, proto: Protocol) -> Result<SocketAddr, PickError> {
+        match proto {
+            Protocol::Tcp => self.ask_tcp(),
+            Protocol::Udp => self.ask_udp(),
+            // Test that the udp port is free as well, on every allowed family
+            Protocol::Both => self
+                .ask_tcp()
+                .and_then(|addr| self.probe_udp(addr.port()).map(|_| addr)),
+        }
+    }
+
+    // Ask the OS for a port free on the given protocol(s) on each of `ips`,
+    // or on the unspecified address if there are none
+    pub(crate) fn ask_on_all(
+        &self,
+        proto: Protocol,
+        ips: &[IpAddr],
+    ) -> Result<SocketAddr, PickError> {
+        let (first, rest) = match ips.split_first() {
+            Some(split) => split,
+            None => return self.ask(proto),
+        };
+        let addr = self.ask_on(proto, *first)?;
+        for ip in rest {
+            self.probe_on(proto, *ip, addr.port())?;
+        }
+        Ok(addr)
+    }
+
+    // Ask the OS for a port free on the given protocol(s) on a single address
+    pub(crate) fn ask_on(&self, proto: Protocol, ip: IpAddr) -> Result<SocketAddr, PickError> {
+        match proto {
+            Protocol::Tcp => self.tcp(SocketAddr::new(ip, 0)),
+            Protocol::Udp => self.udp(SocketAddr::new(ip, 0)),
+            Protocol::Both => {
+                let addr = self.tcp(SocketAddr::new(ip, 0))?;
+                self.udp(addr).map(|_| addr)
+            }
+        }
+    }
+}
+
+// Bind TCP on `[::]` with `IPV6_V6ONLY` off, covering both families with a
+// single socket. `None` if IPv6 or dual-stack sockets aren't supported.
+pub(crate) fn dualstack_tcp(port: Port) -> Option<Result<SocketAddr, PickError>> {
+    if !ipv6_available() {
+        return None;
+    }
+    let addr = SocketAddr::new(UNSPECIFIED_V6, port);
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, None).ok()?;
+    socket.set_only_v6(false).ok()?;
+    let bind = || -> io::Result<SocketAddr> {
+        // Like std's bind, so that `TIME_WAIT` is treated the same
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        #[cfg(windows)]
+        set_exclusive_addr_use(&socket)?;
+        socket.bind(&addr.into())?;
+        socket.listen(128)?;
+        Ok(addr)
+    };
+    let result = bind();
+    drop(socket);
+    Some(result.map_err(|err| PickError::from_bind(addr, err)))
+}
+
+// Bind UDP with every option that would let other sockets share the port off
+fn udp_exclusive(addr: SocketAddr) -> io::Result<SocketAddr> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    socket.set_reuse_address(false)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(false)?;
+    #[cfg(windows)]
+    set_exclusive_addr_use(&socket)?;
+    socket.bind(&addr.into())?;
+    close_socket(socket)
+}
+
+// Read the bound address, then close the socket before the address is returned,
+// so that the caller never races a probe socket that is still open. The probe
+// sockets are never connected, so there's nothing to `shutdown` and closing
+// them frees the port immediately, without any `TIME_WAIT`.
+fn close<S>(socket: S, local_addr: fn(&S) -> io::Result<SocketAddr>) -> io::Result<SocketAddr> {
+    let addr = local_addr(&socket);
+    drop(socket);
+    addr
+}
+
+// `close` for a socket2 socket, returning its address
+pub(crate) fn close_socket(socket: Socket) -> io::Result<SocketAddr> {
+    close(socket, |socket| {
+        socket
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "not an inet socket"))
+    })
+}
+
+// Binding port 0 makes the OS pick another port, so it never counts as free
+fn reject_zero(port: Port) -> Result<(), PickError> {
+    if port == 0 {
+        return Err(PickError::NoPortsAvailable);
+    }
+    Ok(())
+}
+
+// Without SO_EXCLUSIVEADDRUSE, Windows lets a bind succeed on a port that
+// another socket already uses in some cases, making the port look free
+#[cfg(windows)]
+fn set_exclusive_addr_use(socket: &Socket) -> io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+    use windows_sys::Win32::Networking::WinSock::{setsockopt, SOCKET, SOL_SOCKET, SO_REUSEADDR};
+
+    const SO_EXCLUSIVEADDRUSE: i32 = !SO_REUSEADDR;
+    let enable: i32 = 1;
+    // Safety: the socket is valid and `enable` outlives the call
+    let ret = unsafe {
+        setsockopt(
+            socket.as_raw_socket() as SOCKET,
+            SOL_SOCKET,
+            SO_EXCLUSIVEADDRUSE,
+            &enable as *const i32 as *const u8,
+            std::mem::size_of::<i32>() as i32,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use crate::{PickError, Port, PortPicker};
+use std::convert::TryFrom;
+use std::ops::{Range, RangeInclusive};
+
+/// Whether `range` contains any ports, usable in constants
+/// ```rust
+/// use portpicker::validate_range;
+/// use std::ops::Range;
+/// const PORTS: Range<u16> = 15000..16000;
+/// const _: () = assert!(validate_range(&PORTS));
+/// ```
+pub const fn validate_range(range: &Range<u16>) -> bool {
+    range.start < range.end
+}
+
+/// A non-empty, inclusive range of ports, so that reversed or empty ranges are
+/// rejected when it's built rather than when picking from it
+/// ```rust
+/// use portpicker::{pick_unused_port_in, PortRange};
+/// let range = PortRange::new(15000, 16000).expect("reversed range");
+/// let port: u16 = pick_unused_port_in(range).expect("No ports free");
+/// assert!(range.contains(port));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PortRange {
+    low: Port,
+    high: Port,
+}
+
+impl PortRange {
+    /// The ports from `low` to `high` inclusive, or `None` if `low > high`
+    pub const fn new(low: Port, high: Port) -> Option<Self> {
+        if low > high {
+            return None;
+        }
+        Some(PortRange { low, high })
+    }
+
+    /// The lowest port in the range
+    pub const fn low(&self) -> Port {
+        self.low
+    }
+
+    /// The highest port in the range
+    pub const fn high(&self) -> Port {
+        self.high
+    }
+
+    /// Number of ports in the range, at least 1
+    pub const fn count(&self) -> usize {
+        (self.high - self.low) as usize + 1
+    }
+
+    /// Whether `port` lies within the range
+    pub const fn contains(&self, port: Port) -> bool {
+        self.low <= port && port <= self.high
+    }
+}
+
+/// Fails with [`PickError::EmptyRange`] for an empty range
+impl TryFrom<Range<u16>> for PortRange {
+    type Error = PickError;
+
+    fn try_from(range: Range<u16>) -> Result<Self, PickError> {
+        if !validate_range(&range) {
+            return Err(PickError::EmptyRange);
+        }
+        Ok(PortRange {
+            low: range.start,
+            high: range.end - 1,
+        })
+    }
+}
+
+/// Fails with [`PickError::EmptyRange`] for an empty range
+impl TryFrom<RangeInclusive<u16>> for PortRange {
+    type Error = PickError;
+
+    fn try_from(range: RangeInclusive<u16>) -> Result<Self, PickError> {
+        PortRange::new(*range.start(), *range.end()).ok_or(PickError::EmptyRange)
+    }
+}
+
+impl From<PortRange> for RangeInclusive<u16> {
+    fn from(range: PortRange) -> Self {
+        range.low..=range.high
+    }
+}
+
+/// Picks an available port that is available on both TCP and UDP within
+/// `range`, like [`pick_unused_port_range_inclusive`](crate::pick_unused_port_range_inclusive)
+pub fn pick_unused_port_in(range: PortRange) -> Option<Port> {
+    PortPicker::new().port_range(range).pick()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_range, PortRange};
+    use crate::PickError;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn port_range_test() {
+        let (low, high) = (16000, 15000);
+        assert!(!validate_range(&(low..high)));
+        assert!(!validate_range(&(15000..15000)));
+        assert!(validate_range(&(15000..15001)));
+        assert_eq!(PortRange::new(low, high), None);
+
+        let single = PortRange::new(15000, 15000).unwrap();
+        assert_eq!(single.count(), 1);
+        assert!(single.contains(15000) && !single.contains(15001));
+
+        let empty = PortRange::try_from(15000..15000);
+        assert!(matches!(empty, Err(PickError::EmptyRange)));
+        let range = PortRange::try_from(15000..16000).unwrap();
+        assert_eq!((range.low(), range.high()), (15000, 15999));
+        assert_eq!(PortRange::try_from(15000..=15999).unwrap(), range);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::Port;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+// Ports handed out by the picker in this process and not yet released
+static HANDED_OUT: OnceLock<Mutex<HashSet<Port>>> = OnceLock::new();
+
+fn handed_out() -> MutexGuard<'static, HashSet<Port>> {
+    let set = HANDED_OUT.get_or_init(Default::default);
+    // The set stays consistent even if a holder panicked
+    set.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+// Most recently released ports first, capped at `RECENT_CAP`
+static RECENT: OnceLock<Mutex<VecDeque<Port>>> = OnceLock::new();
+const RECENT_CAP: usize = 16;
+
+fn recent() -> MutexGuard<'static, VecDeque<Port>> {
+    let recent = RECENT.get_or_init(Default::default);
+    recent.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+// Mark a port as handed out, false if it already was
+pub(crate) fn claim(port: Port) -> bool {
+    let claimed = handed_out().insert(port);
+    if claimed {
+        recent().retain(|recent| *recent != port);
+    }
+    claimed
+}
+
+// Released ports that may be free again, most recent first
+pub(crate) fn recently_released() -> Vec<Port> {
+    recent().iter().copied().collect()
+}
+
+// Put `port` at the front of `recent`, dropping the oldest beyond the cap
+fn push_recent(recent: &mut VecDeque<Port>, port: Port) {
+    recent.retain(|recent| *recent != port);
+    recent.push_front(port);
+    recent.truncate(RECENT_CAP);
+}
+
+// Whether a port is currently handed out
+#[cfg(feature = "rayon")]
+pub(crate) fn is_claimed(port: Port) -> bool {
+    handed_out().contains(&port)
+}
+
+/// Return a port previously handed out by the picker, so that later picks in
+/// this process may return it again.
+///
+/// Every port returned by [`pick_unused_port`](crate::pick_unused_port) and the
+/// other pickers is remembered, and not returned again from this process until
+/// it's released. This keeps threads picking in parallel from getting the same
+/// port. Dropping a [`PortGuard`](crate::PortGuard) releases its port. Functions
+/// returning an open socket, such as [`bind_unused_tcp`](crate::bind_unused_tcp),
+/// don't remember the port, as the socket itself keeps it from being picked
+/// until it's closed. A [`PortPicker`](crate::PortPicker) can opt out with
+/// [`track(false)`](crate::PortPicker::track).
+///
+/// The last few released ports are tried again first by the next picks, as
+/// they're likely still free. This saves binds in tight pick and release loops.
+pub fn release_port(port: Port) {
+    if handed_out().remove(&port) {
+        push_recent(&mut recent(), port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{push_recent, RECENT_CAP};
+    use std::collections::VecDeque;
+
+    #[test]
+    fn recent_test() {
+        let mut recent = VecDeque::new();
+        for port in 15000..15000 + 2 * RECENT_CAP as u16 {
+            push_recent(&mut recent, port);
+        }
+        assert_eq!(recent.len(), RECENT_CAP);
+        assert_eq!(recent.front(), Some(&(15000 + 2 * RECENT_CAP as u16 - 1)));
+        push_recent(&mut recent, 15020);
+        assert_eq!(recent.len(), RECENT_CAP);
+        assert_eq!(recent.iter().filter(|port| **port == 15020).count(), 1);
+        assert_eq!(recent.front(), Some(&15020));
+    }
+}
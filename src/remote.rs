@@ -0,0 +1,43 @@
+//! Connect-based checks, as opposed to the bind-based checks of the rest of
+//! the crate
+
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// Whether something accepts TCP connections on `addr`, such as a server that
+/// was just started, trying to connect for at most `timeout`.
+///
+/// Unlike [`is_free`](crate::is_free), this also works for remote hosts, and
+/// says nothing about whether the port could be bound locally. A refused
+/// connection means nothing is listening. A timeout is inconclusive, as a
+/// firewall may be dropping the packets, but is reported as not listening
+/// too, as is a zero `timeout`. The connection is closed right away.
+/// ```rust
+/// use portpicker::is_listening;
+/// use std::net::TcpListener;
+/// use std::time::Duration;
+/// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+/// let addr = listener.local_addr().unwrap();
+/// assert!(is_listening(addr, Duration::from_secs(1)));
+/// ```
+pub fn is_listening(addr: SocketAddr, timeout: Duration) -> bool {
+    TcpStream::connect_timeout(&addr, timeout).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_listening;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn listening_test() {
+        let timeout = Duration::from_secs(1);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(is_listening(addr, timeout));
+        assert!(!is_listening(addr, Duration::ZERO));
+        drop(listener);
+        assert!(!is_listening(addr, timeout));
+    }
+}
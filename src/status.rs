@@ -0,0 +1,182 @@
+use crate::probe::{BindOptions, DEFAULT_BIND, UNSPECIFIED_V4, UNSPECIFIED_V6};
+use crate::{ipv6_available, os_ephemeral_range, PickError, Port};
+use std::io;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+
+/// Which of the TCP/UDP and IPv4/IPv6 binds succeeded for a port, as returned
+/// by [`probe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct PortStatus {
+    pub tcp_v4: bool,
+    pub tcp_v6: bool,
+    pub udp_v4: bool,
+    pub udp_v6: bool,
+}
+
+impl PortStatus {
+    /// Free on TCP, as checked by [`is_free_tcp`](crate::is_free_tcp). The
+    /// IPv6 bind is ignored if IPv6 is unavailable on this host.
+    pub fn tcp(&self) -> bool {
+        self.tcp_v4 && (self.tcp_v6 || !ipv6_available())
+    }
+
+    /// Free on UDP, as checked by [`is_free_udp`](crate::is_free_udp)
+    pub fn udp(&self) -> bool {
+        self.udp_v4 && (self.udp_v6 || !ipv6_available())
+    }
+
+    /// Free on both TCP and UDP, as checked by [`is_free`](crate::is_free)
+    pub fn is_free(&self) -> bool {
+        self.tcp() && self.udp()
+    }
+}
+
+/// Try all four binds on the unspecified addresses and report which of them
+/// succeeded. Unlike [`is_free`](crate::is_free), this doesn't stop at the
+/// first failure, which helps diagnosing dual-stack issues. The IPv6 binds are
+/// IPv6-only, so a port taken on IPv4 alone is reported free on IPv6. Port 0
+/// is never reported free.
+/// ```rust
+/// use portpicker::probe;
+/// let status = probe(15000);
+/// println!("TCP over IPv6 free: {}", status.tcp_v6);
+/// ```
+pub fn probe(port: Port) -> PortStatus {
+    // Binding port 0 would test an OS-assigned port instead
+    if port == 0 {
+        return PortStatus::default();
+    }
+    let v4 = SocketAddr::new(UNSPECIFIED_V4, port);
+    let v6 = SocketAddr::new(UNSPECIFIED_V6, port);
+    PortStatus {
+        tcp_v4: DEFAULT_BIND.tcp(v4).is_ok(),
+        tcp_v6: DEFAULT_BIND.tcp_one_family(v6).is_ok(),
+        udp_v4: DEFAULT_BIND.udp(v4).is_ok(),
+        udp_v6: DEFAULT_BIND.udp_one_family(v6).is_ok(),
+    }
+}
+
+/// Check TCP on the unspecified IPv4 and IPv6 addresses independently,
+/// returning `(v4_free, v6_free)`. The IPv6 bind is IPv6-only, so it doesn't
+/// fail just because the port is taken on IPv4. IPv6 is reported busy if it's
+/// unavailable.
+/// ```rust
+/// use portpicker::free_families_tcp;
+/// let (v4_free, _) = free_families_tcp(15000);
+/// if v4_free {
+///     println!("good enough for an IPv4-only server");
+/// }
+/// ```
+pub fn free_families_tcp(port: Port) -> (bool, bool) {
+    free_families(port, BindOptions::tcp_one_family)
+}
+
+/// Like [`free_families_tcp`], but for UDP
+pub fn free_families_udp(port: Port) -> (bool, bool) {
+    free_families(port, BindOptions::udp_one_family)
+}
+
+// Bind on each family of the unspecified address, without stopping at a failure
+fn free_families(
+    port: Port,
+    bind: fn(&BindOptions, SocketAddr) -> Result<SocketAddr, PickError>,
+) -> (bool, bool) {
+    if port == 0 {
+        return (false, false);
+    }
+    let free = |ip| bind(&DEFAULT_BIND, SocketAddr::new(ip, port)).is_ok();
+    (free(UNSPECIFIED_V4), free(UNSPECIFIED_V6))
+}
+
+/// What [`diagnose`] found out about the host's sockets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    /// Why binding a TCP socket to an OS-assigned port failed, or `None` if it
+    /// worked. An error here means no port can be picked at all, as happens
+    /// in sandboxes that deny sockets.
+    pub bind_error: Option<io::ErrorKind>,
+    /// Whether IPv6 sockets can be bound, as checked by [`ipv6_available`]
+    pub ipv6: bool,
+    /// The OS ephemeral range, as read by
+    /// [`os_ephemeral_range`](crate::os_ephemeral_range)
+    pub ephemeral_range: Option<RangeInclusive<u16>>,
+}
+
+impl Diagnosis {
+    /// Whether binding sockets works at all
+    pub fn can_bind(&self) -> bool {
+        self.bind_error.is_none()
+    }
+}
+
+/// Check whether this host lets sockets be bound at all, to tell a sandbox
+/// that blocks them apart from ports that are genuinely busy when a pick
+/// returns `None`. Does a single bind to an OS-assigned port, closed before
+/// returning.
+/// ```rust
+/// use portpicker::{diagnose, pick_unused_port};
+/// if pick_unused_port().is_none() {
+///     eprintln!("no ports free: {:?}", diagnose());
+/// }
+/// ```
+pub fn diagnose() -> Diagnosis {
+    let bind = DEFAULT_BIND.tcp(SocketAddr::new(UNSPECIFIED_V4, 0));
+    Diagnosis {
+        bind_error: bind.err().map(|err| io::Error::from(err).kind()),
+        ipv6: ipv6_available(),
+        ephemeral_range: os_ephemeral_range(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diagnose, free_families_tcp, free_families_udp, probe};
+    use crate::ipv6_available;
+    use std::net::TcpListener;
+
+    #[test]
+    fn status_test() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let status = probe(port);
+        assert!(!status.tcp_v4);
+        assert!(!status.tcp());
+        assert!(!status.is_free());
+    }
+
+    #[test]
+    fn status_v4_only_test() {
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let status = probe(port);
+        assert!(!status.tcp_v4);
+        assert_eq!(status.tcp_v6, ipv6_available());
+    }
+
+    #[test]
+    fn families_test() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (v4_free, _) = free_families_tcp(port);
+        assert!(!v4_free);
+        assert_eq!(free_families_udp(0), (false, false));
+        assert_eq!(free_families_tcp(0), (false, false));
+    }
+
+    #[test]
+    fn families_v4_only_test() {
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(free_families_tcp(port), (false, ipv6_available()));
+    }
+
+    #[test]
+    fn diagnose_test() {
+        let diagnosis = diagnose();
+        assert!(diagnosis.can_bind(), "{:?}", diagnosis);
+        if let Some(range) = diagnosis.ephemeral_range {
+            assert!(range.start() <= range.end());
+        }
+    }
+}
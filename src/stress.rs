@@ -0,0 +1,78 @@
+use crate::reserve_unused_port;
+use std::collections::HashSet;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+/// What [`stress_pick`] observed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct StressReport {
+    /// Ports successfully picked, over all threads
+    pub picks: usize,
+    /// Distinct ports among them
+    pub unique_ports: usize,
+    /// Picks of a port that another thread was still holding
+    pub collisions: usize,
+}
+
+/// Spawn `threads` threads that each pick and hold a port `iterations` times
+/// with [`reserve_unused_port`], counting how often a port was handed out
+/// while another thread was still holding it. This should never happen, so
+/// any collision points at a regression in the reservation registry or the
+/// guards. Needs the `stress` feature.
+/// ```rust
+/// use portpicker::stress_pick;
+/// let report = stress_pick(4, 10);
+/// assert_eq!(report.collisions, 0);
+/// ```
+pub fn stress_pick(threads: usize, iterations: usize) -> StressReport {
+    let held = Mutex::new(HashSet::new());
+    let seen = Mutex::new(HashSet::new());
+    let report = Mutex::new(StressReport::default());
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for _ in 0..iterations {
+                    let guard = match reserve_unused_port() {
+                        Some(guard) => guard,
+                        None => continue,
+                    };
+                    let port = guard.port();
+                    let collided = !lock(&held).insert(port);
+                    lock(&seen).insert(port);
+                    {
+                        let mut report = lock(&report);
+                        report.picks += 1;
+                        report.collisions += collided as usize;
+                    }
+                    // Keep holding the port while the other threads pick
+                    thread::yield_now();
+                    if !collided {
+                        lock(&held).remove(&port);
+                    }
+                    drop(guard);
+                }
+            });
+        }
+    });
+    let mut report = *lock(&report);
+    report.unique_ports = lock(&seen).len();
+    report
+}
+
+// The counters stay consistent even if a thread panicked
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stress_pick;
+
+    #[test]
+    fn stress_test() {
+        let report = stress_pick(8, 20);
+        assert_eq!(report.collisions, 0);
+        assert!(report.picks > 0);
+        assert!(report.unique_ports <= report.picks);
+    }
+}
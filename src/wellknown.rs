@@ -0,0 +1,166 @@
+use crate::{Port, PortPicker};
+use std::collections::HashSet;
+use std::fs;
+
+/// Ports of common services skipped by
+/// [`pick_unused_port_avoiding_wellknown`]:
+///
+/// | Port  | Service                   | Port  | Service                 |
+/// |-------|---------------------------|-------|-------------------------|
+/// | 1080  | SOCKS proxy               | 5984  | CouchDB                 |
+/// | 1433  | Microsoft SQL Server      | 6379  | Redis                   |
+/// | 1521  | Oracle database           | 6443  | Kubernetes API          |
+/// | 1883  | MQTT                      | 8000  | HTTP (alternate)        |
+/// | 2049  | NFS                       | 8080  | HTTP (alternate)        |
+/// | 2181  | ZooKeeper                 | 8443  | HTTPS (alternate)       |
+/// | 2375  | Docker                    | 8888  | HTTP (alternate)        |
+/// | 2376  | Docker over TLS           | 9000  | Various dev servers     |
+/// | 3000  | Various dev servers       | 9042  | Cassandra               |
+/// | 3306  | MySQL                     | 9090  | Prometheus              |
+/// | 3389  | Remote Desktop            | 9092  | Kafka                   |
+/// | 4369  | Erlang port mapper        | 9200  | Elasticsearch HTTP      |
+/// | 5000  | Various dev servers       | 9300  | Elasticsearch transport |
+/// | 5432  | PostgreSQL                | 11211 | memcached               |
+/// | 5672  | AMQP                      | 15672 | RabbitMQ management     |
+/// | 5900  | VNC                       | 27017 | MongoDB                 |
+pub const WELL_KNOWN_PORTS: &[Port] = &[
+    1080, 1433, 1521, 1883, 2049, 2181, 2375, 2376, 3000, 3306, 3389, 4369, 5000, 5432, 5672, 5900,
+    5984, 6379, 6443, 8000, 8080, 8443, 8888, 9000, 9042, 9090, 9092, 9200, 9300, 11211, 15672,
+    27017,
+];
+
+/// The IANA category of a port, as returned by [`port_class`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PortClass {
+    /// System ports below 1024, which need privileges to bind on most Unix
+    /// systems
+    WellKnown,
+    /// User ports from 1024 to 49151, assigned to services by IANA
+    Registered,
+    /// Dynamic ports from 49152 to 65535, never assigned to services
+    Dynamic,
+}
+
+/// The IANA category of `port`. Port 0 counts as well-known.
+/// ```rust
+/// use portpicker::{port_class, PortClass};
+/// assert_eq!(port_class(80), PortClass::WellKnown);
+/// assert_eq!(port_class(8080), PortClass::Registered);
+/// assert_eq!(port_class(50000), PortClass::Dynamic);
+/// ```
+pub const fn port_class(port: Port) -> PortClass {
+    match port {
+        0..=1023 => PortClass::WellKnown,
+        1024..=49151 => PortClass::Registered,
+        _ => PortClass::Dynamic,
+    }
+}
+
+/// Whether binding `port` needs elevated privileges on this platform, to
+/// explain a `PermissionDenied` from [`port_bind_result`](crate::port_bind_result).
+///
+/// On Linux the cutoff is read from
+/// `/proc/sys/net/ipv4/ip_unprivileged_port_start`, defaulting to 1024, and
+/// other Unix systems use 1024. Windows never requires privileges. This
+/// doesn't check whether the current process has them. Port 0 never requires
+/// privileges, as the OS assigns an unprivileged port instead.
+/// ```rust
+/// use portpicker::requires_privilege;
+/// if requires_privilege(80) {
+///     eprintln!("binding port 80 needs root or CAP_NET_BIND_SERVICE");
+/// }
+/// ```
+pub fn requires_privilege(port: Port) -> bool {
+    port != 0 && unprivileged_start().is_some_and(|start| port < start)
+}
+
+// The first port that can be bound without privileges, if there's a cutoff
+#[cfg(target_os = "linux")]
+fn unprivileged_start() -> Option<Port> {
+    let contents = fs::read_to_string("/proc/sys/net/ipv4/ip_unprivileged_port_start");
+    let start = contents
+        .ok()
+        .and_then(|contents| contents.trim().parse().ok());
+    Some(start.unwrap_or(1024))
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn unprivileged_start() -> Option<Port> {
+    Some(1024)
+}
+
+#[cfg(not(unix))]
+fn unprivileged_start() -> Option<Port> {
+    None
+}
+
+/// Picks an available port that is available on both TCP and UDP and is not
+/// one of the [`WELL_KNOWN_PORTS`], which can confuse local port scanners and
+/// firewalls
+/// ```rust
+/// use portpicker::{pick_unused_port_avoiding_wellknown, WELL_KNOWN_PORTS};
+/// let port: u16 = pick_unused_port_avoiding_wellknown().expect("No ports free");
+/// assert!(!WELL_KNOWN_PORTS.contains(&port));
+/// ```
+pub fn pick_unused_port_avoiding_wellknown() -> Option<Port> {
+    PortPicker::new().exclude(WELL_KNOWN_PORTS).pick()
+}
+
+/// Like [`pick_unused_port_avoiding_wellknown`], but also skips every port
+/// listed in `/etc/services`. If that file can't be read, only the
+/// [`WELL_KNOWN_PORTS`] are skipped.
+pub fn pick_unused_port_avoiding_services() -> Option<Port> {
+    let mut picker = PortPicker::new().exclude(WELL_KNOWN_PORTS);
+    if let Ok(contents) = fs::read_to_string("/etc/services") {
+        let listed: Vec<Port> = parse_services(&contents).into_iter().collect();
+        picker = picker.exclude(&listed);
+    }
+    picker.pick()
+}
+
+// Ports listed in the `name port/protocol aliases... # comment` format
+fn parse_services(contents: &str) -> HashSet<Port> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let entry = line.split('#').next()?;
+            let port = entry.split_whitespace().nth(1)?.split('/').next()?;
+            port.parse().ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_services, pick_unused_port_avoiding_services, WELL_KNOWN_PORTS};
+    use super::{port_class, requires_privilege, PortClass};
+
+    #[test]
+    fn services_test() {
+        let services = "\
+# Network services
+ssh\t\t22/tcp\t\t\t# SSH Remote Login Protocol
+http\t\t80/tcp\t\twww\t# WorldWideWeb HTTP
+bogus\t\tnot-a-port/tcp
+# postgresql\t5432/tcp
+";
+        let ports = parse_services(services);
+        assert_eq!(ports.len(), 2);
+        assert!(ports.contains(&22) && ports.contains(&80));
+
+        let port = pick_unused_port_avoiding_services().expect("No ports free");
+        assert!(!WELL_KNOWN_PORTS.contains(&port));
+    }
+
+    #[test]
+    fn port_class_test() {
+        assert_eq!(port_class(0), PortClass::WellKnown);
+        assert_eq!(port_class(1023), PortClass::WellKnown);
+        assert_eq!(port_class(1024), PortClass::Registered);
+        assert_eq!(port_class(49151), PortClass::Registered);
+        assert_eq!(port_class(49152), PortClass::Dynamic);
+        assert_eq!(port_class(65535), PortClass::Dynamic);
+        assert!(!requires_privilege(0));
+        assert!(!requires_privilege(50000));
+    }
+}